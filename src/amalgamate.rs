@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::directive;
+
+const HEADER_EXTENSIONS: [&str; 5] = ["h", "hh", "hpp", "hxx", "h++"];
+
+fn is_header(path: &Path) -> bool {
+    let Some(extension) = path.extension().and_then(|ext| ext.to_str()) else {
+        return false;
+    };
+    HEADER_EXTENSIONS.contains(&extension)
+}
+
+fn list_headers(folder: &Path, headers: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_headers(&path, headers)?;
+        } else if is_header(&path) {
+            headers.push(path.canonicalize()?);
+        }
+    }
+    Ok(())
+}
+
+// headers included by `header`, resolved relative to the header itself or to `root`
+fn included_headers(header: &Path, root: &Path) -> io::Result<Vec<PathBuf>> {
+    let content = fs::read_to_string(header)?;
+    let folder = header.parent().unwrap_or(root);
+
+    let resolve = |name: &str| {
+        [folder, root]
+            .iter()
+            .find_map(|base| base.join(name).canonicalize().ok())
+    };
+
+    let includes = content
+        .lines()
+        .filter_map(directive::include)
+        .filter_map(|include| resolve(include.name))
+        // an `#include_next` of the same name finds another header
+        .filter(|path| path != header)
+        .collect();
+    Ok(includes)
+}
+
+/// headers of `folder` (recursively) that aren't included by any other header of `folder`
+pub fn top_level_headers(folder: &Path) -> io::Result<Vec<PathBuf>> {
+    let root = folder.canonicalize()?;
+    let mut headers = Vec::new();
+    list_headers(&root, &mut headers)?;
+
+    let mut included = HashSet::new();
+    for header in &headers {
+        included.extend(included_headers(header, &root)?);
+    }
+
+    let mut top_level: Vec<PathBuf> = headers
+        .into_iter()
+        .filter(|header| !included.contains(header))
+        .collect();
+    top_level.sort();
+    Ok(top_level)
+}
+
+// line separating the declarations from the implementation sources in the preprocessor output
+pub const IMPLEMENTATION_MARKER: &str = "#pragma single_header implementation";
+
+/// source `#include`-ing all the `files` then the implementation `sources`,
+/// to be fed to the preprocessor through stdin
+pub fn umbrella_source(files: &[PathBuf], sources: &[PathBuf]) -> String {
+    let include = |file: &PathBuf| format!("#include \"{}\"\n", file.display());
+
+    let mut umbrella: String = files.iter().map(include).collect();
+    if !sources.is_empty() {
+        umbrella.push_str(IMPLEMENTATION_MARKER);
+        umbrella.push('\n');
+        umbrella.extend(sources.iter().map(include));
+    }
+    umbrella
+}
@@ -1,8 +1,16 @@
+use std::io::{self, Write};
+use std::path::Path;
+
 #[derive(clap::ValueEnum, Clone, Debug)]
 pub enum Preprocessor {
     Cpp,
     Gcc,
     Clang,
+    // clang expanding `#include`s without evaluating conditionals (`-frewrite-includes`)
+    #[clap(name = "clang-rewrite")]
+    ClangRewrite,
+    // built-in expander, no executable needed
+    Builtin,
 }
 
 impl Preprocessor {
@@ -11,15 +19,76 @@ impl Preprocessor {
             Preprocessor::Cpp => "cpp",
             Preprocessor::Gcc => "gcc",
             Preprocessor::Clang => "clang",
+            Preprocessor::ClangRewrite => "clang-rewrite",
+            Preprocessor::Builtin => "builtin",
+        }
+    }
+
+    /// executable name
+    pub fn program(&self) -> &'static str {
+        match self {
+            Preprocessor::ClangRewrite => "clang",
+            preprocessor => preprocessor.as_str(),
         }
     }
 
     pub fn required_args(&self) -> Vec<&'static str> {
         match self {
-            Preprocessor::Cpp => vec![],
+            Preprocessor::Cpp | Preprocessor::Builtin => vec![],
             Preprocessor::Gcc | Preprocessor::Clang => vec!["-E"],
+            Preprocessor::ClangRewrite => vec!["-E", "-frewrite-includes"],
+        }
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        match self {
+            Preprocessor::Cpp | Preprocessor::Gcc | Preprocessor::Builtin => Dialect::Gnu,
+            Preprocessor::Clang | Preprocessor::ClangRewrite => Dialect::Clang,
+        }
+    }
+
+    /// keeps the conditionals as written instead of evaluating them
+    pub fn rewrites_includes(&self) -> bool {
+        matches!(self, Preprocessor::ClangRewrite)
+    }
+}
+
+/// command line and `-v` output conventions of a preprocessor executable
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    Gnu,
+    Clang,
+}
+
+impl Dialect {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dialect::Gnu => "gnu",
+            Dialect::Clang => "clang",
         }
     }
+
+    /// dialect of `program` guessed from its name: `clang-18`, `arm-none-eabi-gcc`, ...
+    pub fn guess(program: &Path) -> Self {
+        let name = program.file_name().unwrap_or_default().to_string_lossy();
+        match name.contains("clang") {
+            true => Dialect::Clang,
+            false => Dialect::Gnu,
+        }
+    }
+
+    // GNU toolchains select the target with their executable's prefix instead
+    pub fn target_args(&self, triple: &str) -> Vec<String> {
+        match self {
+            Dialect::Gnu => vec![],
+            Dialect::Clang => vec![format!("--target={triple}")],
+        }
+    }
+
+    // `-E` is accepted by compiler drivers and standalone preprocessors alike
+    pub fn required_args(&self) -> Vec<&'static str> {
+        vec!["-E"]
+    }
 }
 
 #[derive(clap::ValueEnum, Clone, Debug)]
@@ -27,6 +96,10 @@ pub enum Lang {
     C,
     #[clap(name = "c++")]
     Cpp,
+    #[clap(name = "objective-c")]
+    ObjC,
+    #[clap(name = "objective-c++")]
+    ObjCpp,
 }
 
 impl Lang {
@@ -34,6 +107,8 @@ impl Lang {
         match self {
             Lang::C => "c",
             Lang::Cpp => "c++",
+            Lang::ObjC => "objective-c",
+            Lang::ObjCpp => "objective-c++",
         }
     }
 }
@@ -45,27 +120,64 @@ pub enum Protection {
 }
 
 impl Protection {
-    pub fn protect(&self, action: impl FnOnce(), filename: String) {
+    pub fn protect<W: Write>(
+        &self,
+        out: &mut W,
+        filename: &str,
+        body: impl FnOnce(&mut W) -> io::Result<()>,
+    ) -> io::Result<()> {
         match self {
-            Protection::Ifndef => ifndef_guard(action, filename),
-            Protection::Once => pragme_once(action),
+            Protection::Ifndef => ifndef_guard(out, filename, body),
+            Protection::Once => pragme_once(out, body),
         }
     }
 }
 
-fn pragme_once(action: impl FnOnce()) {
-    println!("#pragma once");
-    action();
+fn pragme_once<W: Write>(
+    out: &mut W,
+    body: impl FnOnce(&mut W) -> io::Result<()>,
+) -> io::Result<()> {
+    writeln!(out, "#pragma once")?;
+    body(out)
 }
 
-fn ifndef_guard(action: impl FnOnce(), filename: String) {
+fn to_macro_name(name: &str) -> String {
     let invalid_macro_char = |c: char| !char::is_alphanumeric(c) && c != '_';
+    name.to_uppercase().replace(invalid_macro_char, "_")
+}
 
-    let macro_name = filename.to_uppercase().replace(invalid_macro_char, "_");
+fn ifndef_guard<W: Write>(
+    out: &mut W,
+    filename: &str,
+    body: impl FnOnce(&mut W) -> io::Result<()>,
+) -> io::Result<()> {
+    let macro_name = to_macro_name(filename);
     let macro_name = format!("{macro_name}_SINGLE_HEADER"); // prevent collisions with user-land include guards
 
-    println!("#ifndef {macro_name}");
-    println!("# define {macro_name}");
-    action();
-    println!("#endif // {macro_name}");
+    writeln!(out, "#ifndef {macro_name}")?;
+    writeln!(out, "# define {macro_name}")?;
+    body(out)?;
+    writeln!(out, "#endif // {macro_name}")
+}
+
+// STB-style section only compiled by the translation unit defining `<NAME>_IMPLEMENTATION`
+// guarded on its own so it can be included again after the declarations
+pub fn implementation_section<W: Write>(
+    out: &mut W,
+    filename: &str,
+    body: impl FnOnce(&mut W) -> io::Result<()>,
+) -> io::Result<()> {
+    let stem = Path::new(filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let implementation_macro = format!("{}_IMPLEMENTATION", to_macro_name(&stem));
+    let guard = format!("{implementation_macro}_SINGLE_HEADER");
+
+    writeln!(out, "#ifdef {implementation_macro}")?;
+    writeln!(out, "#ifndef {guard}")?;
+    writeln!(out, "# define {guard}")?;
+    body(out)?;
+    writeln!(out, "#endif // {guard}")?;
+    writeln!(out, "#endif // {implementation_macro}")
 }
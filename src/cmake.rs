@@ -3,7 +3,9 @@ use lazy_static::lazy_static;
 use radix_trie::{Trie, TrieCommon};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct CompileCommand {
@@ -27,7 +29,7 @@ lazy_static! {
     static ref PREPROCESSING_ARGS: Trie<String, ()> = supported_args_trie();
 }
 
-fn read_compile_commands(path: PathBuf, file: PathBuf) -> Result<Vec<String>> {
+fn read_compile_commands(path: &Path, file: PathBuf) -> Result<Vec<String>> {
     let commands: Vec<CompileCommand> = serde_json::from_reader(File::open(path)?)?;
 
     let Some(command) = commands.into_iter().find(|cmd| file == cmd.file) else {
@@ -67,25 +69,27 @@ fn read_compile_commands(path: PathBuf, file: PathBuf) -> Result<Vec<String>> {
     Ok(saved_args)
 }
 
-pub fn cmake_options(cmake_arg: Option<PathBuf>, file: &str) -> Result<Vec<String>> {
-    let Some(cmake) = cmake_arg else {
-        return Ok(Vec::new());
+/// `compile_commands.json` of the cmake build folder
+pub fn compile_commands(cmake: &Path) -> crate::error::Result<PathBuf> {
+    let error = |reason| Error::CmakeFolder {
+        path: cmake.to_path_buf(),
+        reason,
     };
     if !cmake.exists() {
-        let err = anyhow!("cmake path does not exist: {}", cmake.display());
-        return Err(err);
+        return Err(error("it does not exist"));
     }
     if !cmake.is_dir() {
-        let err = anyhow!("cmake path doesn't point to a folder: {}", cmake.display());
-        return Err(err);
+        return Err(error("it is not a folder"));
     }
-
     let compile_commands = cmake.join("compile_commands.json");
     if !compile_commands.exists() {
-        let err = anyhow!("compile_commands.json not found in {}", cmake.display());
-        return Err(err);
+        return Err(error("compile_commands.json not found"));
     }
+    Ok(compile_commands)
+}
 
+// a file without entry, like a header, gets no options
+pub fn cmake_options(compile_commands: &Path, file: &str) -> Result<Vec<String>> {
     let file = PathBuf::from(file).canonicalize()?; // absolute path to file
     read_compile_commands(compile_commands, file)
 }
@@ -0,0 +1,159 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::expand::strip_comments;
+
+// Parsing of the source directives, shared by the include resolution, the spelling recovery,
+// the top-level header detection and the conditional tracking
+
+lazy_static! {
+    static ref INCLUDE_REGEX: Regex =
+        Regex::new(r#"^\s*#\s*(include_next|include|import)\s*(?:"([^"]*)"|<([^>]*)>)"#).unwrap();
+    /// kind of a conditional directive and the rest of its line
+    pub static ref CONDITIONAL_REGEX: Regex =
+        Regex::new(r"^\s*#\s*(ifdef|ifndef|if|elifdef|elifndef|elif|else|endif)\b(.*)$").unwrap();
+    static ref MACRO_REGEX: Regex = Regex::new(r"^\s*#\s*(define|undef)\s+(\w+)").unwrap();
+    static ref GUARD_REGEX: Regex =
+        Regex::new(r"^\s*#\s*(?:ifndef\s+(\w+)|if\s+!\s*defined\s*\(?\s*(\w+)\s*\)?)\s*$").unwrap();
+}
+
+/// `#include`, `#include_next` or `#import` directive with a `"..."` or `<...>` name
+pub struct Include<'a> {
+    pub name: &'a str,
+    pub quoted: bool,
+    pub next: bool,
+    pub import: bool,
+}
+
+pub fn include(code: &str) -> Option<Include<'_>> {
+    let captures = INCLUDE_REGEX.captures(code)?;
+    let (name, quoted) = match (captures.get(2), captures.get(3)) {
+        (Some(name), _) => (name.as_str(), true),
+        (_, Some(name)) => (name.as_str(), false),
+        _ => return None,
+    };
+    let directive = captures.get(1)?.as_str();
+    Some(Include {
+        name,
+        quoted,
+        next: directive == "include_next",
+        import: directive == "import",
+    })
+}
+
+/// how a conditional directive changes the nesting
+#[derive(PartialEq)]
+pub enum Conditional {
+    Open,   // `#if`, `#ifdef`, `#ifndef`
+    Branch, // `#elif`, `#else`, ...
+    Close,  // `#endif`
+}
+
+pub fn conditional(code: &str) -> Option<Conditional> {
+    let captures = CONDITIONAL_REGEX.captures(code)?;
+    Some(match &captures[1] {
+        "if" | "ifdef" | "ifndef" => Conditional::Open,
+        "endif" => Conditional::Close,
+        _ => Conditional::Branch,
+    })
+}
+
+/// preserved conditionals a line is in
+#[derive(Default)]
+pub struct Conditionals {
+    // `true` for the include guards, they don't make the lines conditional
+    stack: Vec<bool>,
+}
+
+impl Conditionals {
+    /// `is_guard` tells whether the macro tested by a `#ifndef` is an include guard
+    pub fn feed(&mut self, conditional: Conditional, code: &str, is_guard: impl Fn(&str) -> bool) {
+        match conditional {
+            Conditional::Open => self.stack.push(guard_name(code).is_some_and(is_guard)),
+            Conditional::Branch => {
+                // the `#else` of an include guard is conditional
+                if let Some(guard) = self.stack.last_mut() {
+                    *guard = false;
+                }
+            }
+            Conditional::Close => {
+                self.stack.pop();
+            }
+        }
+    }
+
+    /// the line is in a conditional other than an include guard
+    pub fn is_conditional(&self) -> bool {
+        self.stack.contains(&false)
+    }
+}
+
+/// `#define` or `#undef` directive, and the macro name
+pub fn macro_definition(code: &str) -> Option<(bool, &str)> {
+    let captures = MACRO_REGEX.captures(code)?;
+    let define = captures.get(1)?.as_str() == "define";
+    Some((define, captures.get(2)?.as_str()))
+}
+
+/// macro tested by a `#ifndef X` / `#if !defined(X)` line
+pub fn guard_name(code: &str) -> Option<&str> {
+    let captures = GUARD_REGEX.captures(code)?;
+    Some(captures.get(1).or(captures.get(2))?.as_str())
+}
+
+/// include guard of a file: `#ifndef X` followed by `#define X` before any other code
+pub fn include_guard<S: AsRef<str>>(lines: &[S]) -> Option<String> {
+    let mut in_comment = false;
+    let mut code = lines
+        .iter()
+        .map(|line| strip_comments(line.as_ref(), &mut in_comment))
+        .filter(|code| !code.trim().is_empty());
+    let guard = String::from(guard_name(&code.next()?)?);
+    let define = code.next()?;
+    match macro_definition(&define)? {
+        (true, name) if name == guard => Some(guard),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes() {
+        let parsed = include(" # include_next <sys/types.h> // next").unwrap();
+        assert_eq!(parsed.name, "sys/types.h");
+        assert!(parsed.next && !parsed.quoted && !parsed.import);
+
+        let parsed = include("#import \"lib.h\"").unwrap();
+        assert_eq!(parsed.name, "lib.h");
+        assert!(parsed.import && parsed.quoted && !parsed.next);
+
+        assert!(include("#include MACRO").is_none());
+        assert!(include("#includes <a.h>").is_none());
+    }
+
+    #[test]
+    fn conditionals() {
+        assert!(conditional("#ifdef X") == Some(Conditional::Open));
+        assert!(conditional("  #  elifndef X") == Some(Conditional::Branch));
+        assert!(conditional("#endif // X") == Some(Conditional::Close));
+        assert!(conditional("#ifdefined").is_none());
+    }
+
+    #[test]
+    fn finds_include_guards() {
+        let guard = |content: &str| include_guard(&content.lines().collect::<Vec<_>>());
+        assert_eq!(
+            guard("// lib\n#ifndef LIB_H\n#define LIB_H\n#endif"),
+            Some(String::from("LIB_H"))
+        );
+        assert_eq!(
+            guard("#if !defined(LIB_H)\n#define LIB_H 1\n#endif"),
+            Some(String::from("LIB_H"))
+        );
+        assert_eq!(guard("#ifndef LIB_H\n#define OTHER\n#endif"), None);
+        assert_eq!(guard("#pragma once\n#define LIB_H"), None);
+    }
+}
@@ -0,0 +1,114 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+/// Errors returned by the [`SingleHeader`](crate::SingleHeader) builder
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to find preprocessor `{name}` in PATH")]
+    PreprocessorNotFound {
+        name: String,
+        #[source]
+        source: which::Error,
+    },
+
+    #[error("Failed to read input {}", .path.display())]
+    Input {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("No top-level header found in the inputs")]
+    NoHeaders,
+
+    #[error("Invalid macro name `{0}`")]
+    InvalidMacro(String),
+
+    #[error("Invalid pattern `{pattern}`")]
+    InvalidPattern {
+        pattern: String,
+        #[source]
+        source: globset::Error,
+    },
+
+    #[error("`{0}` is neither an existing path nor a header of the search paths")]
+    UnknownPath(String),
+
+    #[error("Invalid source map {}", .path.display())]
+    InvalidSourceMap {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("Invalid cmake build folder {}: {reason}", .path.display())]
+    CmakeFolder { path: PathBuf, reason: &'static str },
+
+    #[error("Failed to read compile options from cmake build folder")]
+    Cmake(#[source] anyhow::Error),
+
+    #[error("Failed to run {description}")]
+    Command {
+        description: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{description} exited with {status}:\n{stderr}")]
+    CommandFailed {
+        description: &'static str,
+        status: ExitStatus,
+        stderr: String,
+    },
+
+    #[error("`--target {target}` can't be applied to `{name}`, GNU toolchains are selected by their `{target}-` prefix")]
+    UnsupportedTarget { target: String, name: String },
+
+    #[error("`{name}` is not a supported preprocessor: {reason}")]
+    UnsupportedPreprocessor { name: String, reason: &'static str },
+
+    #[error("Preprocessor search path {} doesn't exist", .path.display())]
+    SearchPath {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{location}: system header {} is not in the preprocessor search paths", .header.display())]
+    UnresolvedSystemHeader { header: PathBuf, location: String },
+
+    #[error("Failed to read the current directory")]
+    CurrentDir(#[source] io::Error),
+
+    #[error("Failed to write single header")]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    /// process exit code for the error:
+    /// 2 for usage errors, 3 for preprocessor failures and 1 for other processing failures
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Input { .. }
+            | Error::NoHeaders
+            | Error::InvalidMacro(_)
+            | Error::InvalidPattern { .. }
+            | Error::UnknownPath(_)
+            | Error::CmakeFolder { .. }
+            | Error::UnsupportedTarget { .. } => 2,
+            Error::PreprocessorNotFound { .. }
+            | Error::Command { .. }
+            | Error::CommandFailed { .. }
+            | Error::UnsupportedPreprocessor { .. }
+            | Error::SearchPath { .. } => 3,
+            Error::UnresolvedSystemHeader { .. }
+            | Error::InvalidSourceMap { .. }
+            | Error::Cmake(_)
+            | Error::CurrentDir(_)
+            | Error::Io(_) => 1,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
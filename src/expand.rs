@@ -0,0 +1,477 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::directive::{self, Conditional};
+use crate::path_filter::PathFilter;
+use crate::system_paths::SearchPaths;
+use crate::utils;
+
+// Directive-aware `#include` expander
+// Inlines headers like the preprocessor would but keeps every conditional as written,
+// and outputs the same linemarkers as `cpp -fdirectives-only` so `process_lines` can consume it
+
+lazy_static! {
+    static ref PRAGMA_ONCE_REGEX: Regex = Regex::new(r"^\s*#\s*pragma\s+once\b").unwrap();
+}
+
+/// directories `#include` directives are resolved against
+pub struct IncludeDirs {
+    quote: Vec<PathBuf>,  // `-iquote`: only searched by `#include "..."`
+    local: Vec<PathBuf>,  // `-I`: part of the search list but not system directories
+    search: Vec<PathBuf>, // `#include <...>` search list
+}
+
+/// file an `#include` finds, `system` if it was found through a system directory
+pub struct Resolved {
+    pub path: PathBuf,
+    pub system: bool,
+}
+
+impl IncludeDirs {
+    pub fn new(search_paths: &SearchPaths) -> Self {
+        IncludeDirs {
+            quote: search_paths.quote_paths().to_vec(),
+            local: search_paths.local_paths().to_vec(),
+            search: search_paths.ordered_paths().to_vec(),
+        }
+    }
+
+    fn is_system_dir(&self, dir: &Path) -> bool {
+        !self.local.iter().any(|local| local == dir)
+    }
+
+    fn is_system_path(&self, path: &Path) -> bool {
+        self.search
+            .iter()
+            .any(|dir| self.is_system_dir(dir) && path.starts_with(dir))
+    }
+
+    /// file `include` of `path` finds, `system` if `path` is a system header
+    pub fn resolve(
+        &self,
+        include: &directive::Include,
+        path: &Path,
+        system: bool,
+    ) -> Option<Resolved> {
+        let folder = path.parent();
+        let (name, quoted) = (include.name, include.quoted);
+
+        if Path::new(name).is_absolute() {
+            let path = Path::new(name).canonicalize().ok()?;
+            let system = self.is_system_path(&path);
+            return Some(Resolved { path, system });
+        }
+
+        let find = |dir: &Path| dir.join(name).canonicalize().ok();
+
+        if include.next {
+            // `#include_next` searches the directories following the one `path` was found in,
+            // or all of them if it wasn't found through the search list
+            let skip = self
+                .search
+                .iter()
+                .position(|dir| path.starts_with(dir))
+                .map_or(0, |index| index + 1);
+            return self.search[skip..].iter().find_map(|dir| {
+                find(dir).map(|path| Resolved {
+                    path,
+                    system: self.is_system_dir(dir),
+                })
+            });
+        }
+
+        if quoted {
+            if let Some(path) = folder.and_then(find) {
+                return Some(Resolved { path, system });
+            }
+            if let Some(path) = self.quote.iter().find_map(|dir| find(dir)) {
+                return Some(Resolved {
+                    path,
+                    system: false,
+                });
+            }
+        }
+
+        self.search.iter().find_map(|dir| {
+            find(dir).map(|path| Resolved {
+                path,
+                system: self.is_system_dir(dir),
+            })
+        })
+    }
+}
+
+// returns the part of `line` that isn't a comment, `in_comment` tracks multi-line `/* */` comments
+pub fn strip_comments(line: &str, in_comment: &mut bool) -> String {
+    let mut code = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        if *in_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                *in_comment = false;
+                code.push(' ');
+            }
+            continue;
+        }
+        match (quote, c) {
+            (Some(_), '\\') => {
+                code.push(c);
+                code.extend(chars.next());
+            }
+            (Some(q), c) => {
+                if q == c {
+                    quote = None;
+                }
+                code.push(c);
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                code.push(c);
+            }
+            (None, '/') if chars.peek() == Some(&'/') => break,
+            (None, '/') if chars.peek() == Some(&'*') => {
+                chars.next();
+                *in_comment = true;
+            }
+            (None, c) => code.push(c),
+        }
+    }
+    code
+}
+
+const ONCE_GUARD_PREFIX: &str = "SINGLE_HEADER_ONCE_";
+
+/// the macro is an `#ifndef` guard replacing a `#pragma once` or `#import`
+pub fn is_once_guard(name: &str) -> bool {
+    name.starts_with(ONCE_GUARD_PREFIX)
+}
+
+// `#pragma once` outside of comments
+fn has_pragma_once(content: &str) -> bool {
+    let mut in_comment = false;
+    content
+        .lines()
+        .any(|line| PRAGMA_ONCE_REGEX.is_match(&strip_comments(line, &mut in_comment)))
+}
+
+fn linemarker(linenum: usize, path: &Path, flags: &str) -> String {
+    let filename = utils::escape_string(&path.display().to_string());
+    format!("# {linenum} \"{filename}\" {flags}")
+        .trim_end()
+        .to_string()
+}
+
+struct Expander<'a> {
+    dirs: &'a IncludeDirs,
+    inline_paths: &'a PathFilter,
+    exclude_paths: &'a PathFilter,
+    // `#ifndef` guards replacing the `#pragma once` or `#import` of files
+    once_guards: HashMap<PathBuf, String>,
+    // preserved conditionals the next line is in, as (conditional, branch) indexes,
+    // `None` for the include guards of the files
+    conditions: Vec<Option<(usize, usize)>>,
+    conditional_count: usize,
+    // conditions under which include-once files were inlined
+    inlined: HashMap<PathBuf, Vec<Vec<(usize, usize)>>>,
+    stack: Vec<PathBuf>,
+    output: Vec<String>,
+}
+
+// an include guard, `#pragma once` or `#import` keeps the file from being included twice
+fn is_include_once(content: &str, import: bool) -> bool {
+    let lines: Vec<&str> = content.lines().collect();
+    import || has_pragma_once(content) || directive::include_guard(&lines).is_some()
+}
+
+impl<'a> Expander<'a> {
+    fn expand(&mut self, path: &Path, content: &str, system: bool) -> io::Result<()> {
+        let mut in_comment = false;
+        let depth = self.conditions.len();
+        let lines: Vec<&str> = content.lines().collect();
+        let mut guarded = directive::include_guard(&lines).is_some();
+
+        for (index, line) in content.lines().enumerate() {
+            let linenum = index + 1;
+            let was_in_comment = in_comment;
+            let code = strip_comments(line, &mut in_comment);
+
+            let is_directive = !was_in_comment && code.trim_start().starts_with('#');
+            if !is_directive {
+                self.output.push(String::from(line));
+                continue;
+            }
+            if PRAGMA_ONCE_REGEX.is_match(&code) {
+                self.output.push(String::new());
+                continue;
+            }
+            if let Some(conditional) = directive::conditional(&code) {
+                // the include guard is the first conditional
+                let guard = std::mem::take(&mut guarded);
+                self.track_conditional(conditional, guard);
+                self.output.push(String::from(line));
+                continue;
+            }
+            // computed or unresolved includes are kept as written
+            let Some(directive) = directive::include(&code) else {
+                self.output.push(String::from(line));
+                continue;
+            };
+            let Some(include) = self.dirs.resolve(&directive, path, system) else {
+                self.output.push(String::from(line));
+                continue;
+            };
+            if self.include(&include, directive.import)? {
+                self.output.push(linemarker(linenum + 1, path, "2"));
+            } else {
+                self.output.push(String::new());
+            }
+        }
+        self.conditions.truncate(depth);
+        Ok(())
+    }
+
+    fn track_conditional(&mut self, conditional: Conditional, guard: bool) {
+        match conditional {
+            Conditional::Open if guard => self.conditions.push(None),
+            Conditional::Open => {
+                self.conditions.push(Some((self.conditional_count, 0)));
+                self.conditional_count += 1;
+            }
+            Conditional::Branch => {
+                if let Some(Some((_, branch))) = self.conditions.last_mut() {
+                    *branch += 1;
+                }
+            }
+            Conditional::Close => {
+                self.conditions.pop();
+            }
+        }
+    }
+
+    // whether an include-once file was inlined before under the conditions of the next line,
+    // making this inclusion a no-op
+    fn already_inlined(&mut self, path: &Path) -> bool {
+        let conditions: Vec<(usize, usize)> = self.conditions.iter().flatten().copied().collect();
+        let inlined = self.inlined.entry(path.to_path_buf()).or_default();
+        if inlined.iter().any(|before| conditions.starts_with(before)) {
+            return true;
+        }
+        inlined.push(conditions);
+        false
+    }
+
+    // `#ifndef` guard of a `#pragma once` or `#import`ed file, named after its first inclusion
+    fn once_guard(&mut self, path: &Path) -> String {
+        let count = self.once_guards.len();
+        let guard = self
+            .once_guards
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let name: String = path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .chars()
+                    .map(|c| match c.is_ascii_alphanumeric() {
+                        true => c.to_ascii_uppercase(),
+                        false => '_',
+                    })
+                    .collect();
+                format!("{ONCE_GUARD_PREFIX}{count}_{name}")
+            });
+        guard.clone()
+    }
+
+    // returns false if the file was skipped because it includes itself or was already inlined
+    // as the conditionals are kept, an include-once file is inlined again under other conditionals:
+    // include guards are kept as written, `#pragma once` and `#import` are replaced by an `#ifndef` guard
+    fn include(&mut self, include: &Resolved, import: bool) -> io::Result<bool> {
+        let Resolved { path, system } = include;
+        let flags = if *system { "1 3" } else { "1" };
+
+        let external = if *system {
+            !self.inline_paths.contains(path)
+        } else {
+            self.exclude_paths.contains(path)
+        };
+        if external {
+            // content is replaced by an `#include` directive by `process_lines`
+            self.output.push(linemarker(1, path, flags));
+            return Ok(true);
+        }
+        if self.stack.contains(path) {
+            return Ok(false);
+        }
+
+        let content = fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        if is_include_once(&content, import) && self.already_inlined(path) {
+            return Ok(false);
+        }
+        // before the linemarker to keep the line numbers of the file
+        let guard = (import || has_pragma_once(&content)).then(|| self.once_guard(path));
+        if let Some(guard) = &guard {
+            self.output.push(format!("#ifndef {guard}"));
+            self.output.push(format!("#define {guard}"));
+        }
+
+        self.output.push(linemarker(1, path, flags));
+        self.stack.push(path.clone());
+        self.expand(path, &content, *system)?;
+        self.stack.pop();
+        if guard.is_some() {
+            self.output.push(String::from("#endif"));
+        }
+        Ok(true)
+    }
+}
+
+/// expand the local `#include`s of `source`, read from `path`, without evaluating conditionals
+pub fn expand_source(
+    path: &Path,
+    source: &str,
+    dirs: &IncludeDirs,
+    inline_paths: &PathFilter,
+    exclude_paths: &PathFilter,
+) -> io::Result<Vec<String>> {
+    let mut expander = Expander {
+        dirs,
+        inline_paths,
+        exclude_paths,
+        once_guards: HashMap::new(),
+        conditions: Vec::new(),
+        conditional_count: 0,
+        inlined: HashMap::new(),
+        stack: vec![path.to_path_buf()],
+        output: vec![linemarker(1, path, "")],
+    };
+    expander.expand(path, source, false)?;
+    Ok(expander.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // expands `main.h` of a folder holding `files`
+    fn expand(files: &[(&str, &str)]) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+        let main = dir.path().join("main.h").canonicalize().unwrap();
+        let source = fs::read_to_string(&main).unwrap();
+        let dirs = IncludeDirs::new(&SearchPaths::from_options(&[]));
+        let filter = PathFilter::default();
+        expand_source(&main, &source, &dirs, &filter, &filter).unwrap()
+    }
+
+    fn count(lines: &[String], pattern: &str) -> usize {
+        lines.iter().filter(|line| line.contains(pattern)).count()
+    }
+
+    const MAIN: &str =
+        "#ifdef _WIN32\n#include \"common.h\"\n#else\n#include \"common.h\"\n#endif\n";
+
+    #[test]
+    fn guarded_header_in_both_branches() {
+        let lines = expand(&[
+            ("main.h", MAIN),
+            (
+                "common.h",
+                "#ifndef COMMON_H\n#define COMMON_H\nstruct Common {};\n#endif\n",
+            ),
+        ]);
+        assert_eq!(count(&lines, "struct Common"), 2);
+        assert_eq!(count(&lines, "#ifndef COMMON_H"), 2);
+        assert_eq!(count(&lines, "SINGLE_HEADER_ONCE"), 0);
+    }
+
+    #[test]
+    fn pragma_once_header_in_both_branches() {
+        let lines = expand(&[
+            ("main.h", MAIN),
+            ("common.h", "#pragma once\nstruct Common {};\n"),
+        ]);
+        assert_eq!(count(&lines, "struct Common"), 2);
+        assert_eq!(count(&lines, "#pragma once"), 0);
+        let guards: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("#ifndef "))
+            .collect();
+        assert_eq!(guards, ["#ifndef SINGLE_HEADER_ONCE_0_COMMON_H"; 2]);
+        assert_eq!(count(&lines, "#define SINGLE_HEADER_ONCE_0_COMMON_H"), 2);
+    }
+
+    #[test]
+    fn imported_header_is_guarded() {
+        let lines = expand(&[
+            ("main.h", &MAIN.replace("#include", "#import")),
+            ("common.h", "struct Common {};\n"),
+        ]);
+        assert_eq!(count(&lines, "struct Common"), 2);
+        assert_eq!(count(&lines, "#ifndef SINGLE_HEADER_ONCE_0_COMMON_H"), 2);
+        assert_eq!(count(&lines, "#endif"), 3); // and the one of `main.h`
+    }
+
+    #[test]
+    fn include_once_header_is_inlined_once_outside_conditionals() {
+        let common = "#ifndef COMMON_H\n#define COMMON_H\nstruct Common {};\n#endif\n";
+        let lines = expand(&[
+            (
+                "main.h",
+                "#include \"common.h\"\n#ifdef _WIN32\n#include \"common.h\"\n#endif\n#import \"common.h\"\n",
+            ),
+            ("common.h", common),
+        ]);
+        assert_eq!(count(&lines, "struct Common"), 1);
+
+        // the first inclusion is conditional, the second one isn't
+        let lines = expand(&[
+            (
+                "main.h",
+                "#ifdef _WIN32\n#include \"common.h\"\n#include \"common.h\"\n#endif\n#include \"common.h\"\n",
+            ),
+            ("common.h", common),
+        ]);
+        assert_eq!(count(&lines, "struct Common"), 2);
+    }
+
+    #[test]
+    fn diamond_includes_stay_linear() {
+        // `h{i}` includes `h{i + 1}` and `h{i + 2}`, from inside its include guard
+        let headers: Vec<(String, String)> = (0..30)
+            .map(|i| {
+                let includes: String = [i + 1, i + 2]
+                    .iter()
+                    .filter(|next| **next < 30)
+                    .map(|next| format!("#include \"h{next}.h\"\n"))
+                    .collect();
+                let content =
+                    format!("#ifndef H{i}\n#define H{i}\n{includes}struct H{i} {{}};\n#endif\n");
+                (format!("h{i}.h"), content)
+            })
+            .collect();
+        let mut files: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(name, content)| (name.as_str(), content.as_str()))
+            .collect();
+        files.push(("main.h", "#include \"h0.h\"\n"));
+        let lines = expand(&files);
+        assert_eq!(count(&lines, "struct H29 {}"), 1);
+        assert!(lines.len() < 30 * 10);
+    }
+
+    #[test]
+    fn self_include_is_skipped() {
+        let lines = expand(&[("main.h", "#include \"main.h\"\nint main_h;\n")]);
+        assert_eq!(count(&lines, "int main_h"), 1);
+    }
+}
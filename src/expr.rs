@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+
+// Preprocessor `#if` expressions: parsing, partial evaluation and printing
+
+/// macros with a known state: `Some(value)` if defined, `None` if undefined
+pub type KnownMacros = HashMap<String, Option<String>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Int(i64, String), // value and its spelling
+    Defined(String),
+    Ident(String),
+    Call(String, String), // function-like macro with its raw arguments, e.g. `__has_include(<x>)`
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TokenKind {
+    Number,
+    Char,
+    Ident,
+    Punct,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    start: usize,
+}
+
+const PUNCTUATORS: [&str; 26] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "!", "~", "-", "+", "*", "/", "%", "<", ">",
+    "&", "^", "|", "?", ":", "(", ")", ",", ".",
+];
+
+fn tokenize(input: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let bytes = input.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let start = i;
+        let kind = if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            TokenKind::Ident
+        } else if c.is_ascii_digit() {
+            // pp-number
+            while i < bytes.len()
+                && (bytes[i].is_ascii_alphanumeric() || matches!(bytes[i], b'.' | b'\'' | b'_'))
+            {
+                i += 1;
+            }
+            TokenKind::Number
+        } else if c == '\'' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != b'\'' {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+            if i > bytes.len() {
+                return None;
+            }
+            TokenKind::Char
+        } else {
+            let punct = PUNCTUATORS.iter().find(|p| input[i..].starts_with(**p))?;
+            i += punct.len();
+            TokenKind::Punct
+        };
+        tokens.push(Token {
+            kind,
+            text: &input[start..i],
+            start,
+        });
+    }
+    Some(tokens)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let digits = text
+        .replace('\'', "")
+        .trim_end_matches(['u', 'U', 'l', 'L', 'z', 'Z'])
+        .to_lowercase();
+    let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x") {
+        (hex.to_string(), 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        (binary.to_string(), 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (digits[1..].to_string(), 8)
+    } else {
+        (digits, 10)
+    };
+    u64::from_str_radix(&digits, radix).ok().map(|n| n as i64)
+}
+
+fn parse_char(text: &str) -> Option<i64> {
+    let inner = text.strip_prefix('\'')?.strip_suffix('\'')?;
+    let value = match inner.strip_prefix('\\') {
+        None => {
+            let mut chars = inner.chars();
+            let c = chars.next()?;
+            chars.next().is_none().then_some(c as i64)?
+        }
+        Some("n") => 10,
+        Some("t") => 9,
+        Some("r") => 13,
+        Some("0") => 0,
+        Some("\\") => 92,
+        Some("'") => 39,
+        Some("\"") => 34,
+        Some(_) => return None,
+    };
+    Some(value)
+}
+
+// binary operators from lowest to highest precedence
+const BINARY_LEVELS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+const CONDITIONAL_PRECEDENCE: usize = 0;
+const UNARY_PRECEDENCE: usize = BINARY_LEVELS.len() + 1;
+const PRIMARY_PRECEDENCE: usize = UNARY_PRECEDENCE + 1;
+
+fn binary_precedence(op: &str) -> usize {
+    let level = BINARY_LEVELS.iter().position(|ops| ops.contains(&op));
+    level.expect("unknown binary operator") + 1
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = self
+            .peek()
+            .is_some_and(|t| t.kind == TokenKind::Punct && t.text == punct);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.pos).copied();
+        self.pos += 1;
+        token
+    }
+
+    fn conditional(&mut self) -> Option<Expr> {
+        let condition = self.binary(0)?;
+        if !self.eat("?") {
+            return Some(condition);
+        }
+        let then = self.conditional()?;
+        if !self.eat(":") {
+            return None;
+        }
+        let otherwise = self.conditional()?;
+        Some(Expr::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn binary(&mut self, level: usize) -> Option<Expr> {
+        let Some(ops) = BINARY_LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = ops.iter().find(|op| self.eat(op)) {
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        for op in ["!", "~", "-", "+"] {
+            if self.eat(op) {
+                return Some(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Option<Expr> {
+        let token = self.next()?;
+        match token.kind {
+            TokenKind::Number => Some(Expr::Int(parse_number(token.text)?, token.text.into())),
+            TokenKind::Char => Some(Expr::Int(parse_char(token.text)?, token.text.into())),
+            TokenKind::Ident if token.text == "defined" => {
+                let parenthesized = self.eat("(");
+                let name = self.next().filter(|t| t.kind == TokenKind::Ident)?;
+                if parenthesized && !self.eat(")") {
+                    return None;
+                }
+                Some(Expr::Defined(name.text.into()))
+            }
+            TokenKind::Ident => {
+                if !self.eat("(") {
+                    return Some(Expr::Ident(token.text.into()));
+                }
+                let start = self.peek()?.start;
+                let mut depth = 1;
+                loop {
+                    let arg = self.next()?;
+                    match arg.text {
+                        "(" => depth += 1,
+                        ")" => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 && arg.kind == TokenKind::Punct {
+                        let args = &self.input[start..arg.start];
+                        return Some(Expr::Call(token.text.into(), args.trim().into()));
+                    }
+                }
+            }
+            TokenKind::Punct if token.text == "(" => {
+                let expr = self.conditional()?;
+                self.eat(")").then_some(expr)
+            }
+            TokenKind::Punct => None,
+        }
+    }
+}
+
+/// parse a `#if` expression, `None` if it isn't a valid expression
+pub fn parse(input: &str) -> Option<Expr> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        input,
+        tokens,
+        pos: 0,
+    };
+    let expr = parser.conditional()?;
+    (parser.pos == parser.tokens.len()).then_some(expr)
+}
+
+fn int(value: i64) -> Expr {
+    Expr::Int(value, value.to_string())
+}
+
+fn as_int(expr: &Expr) -> Option<i64> {
+    match expr {
+        Expr::Int(value, _) => Some(*value),
+        _ => None,
+    }
+}
+
+fn apply_binary(op: &str, l: i64, r: i64) -> Option<i64> {
+    let value = match op {
+        "||" => (l != 0 || r != 0) as i64,
+        "&&" => (l != 0 && r != 0) as i64,
+        "|" => l | r,
+        "^" => l ^ r,
+        "&" => l & r,
+        "==" => (l == r) as i64,
+        "!=" => (l != r) as i64,
+        "<" => (l < r) as i64,
+        ">" => (l > r) as i64,
+        "<=" => (l <= r) as i64,
+        ">=" => (l >= r) as i64,
+        "<<" => l.checked_shl(u32::try_from(r).ok()?)?,
+        ">>" => l.checked_shr(u32::try_from(r).ok()?)?,
+        "+" => l.wrapping_add(r),
+        "-" => l.wrapping_sub(r),
+        "*" => l.wrapping_mul(r),
+        "/" => l.checked_div(r)?,
+        "%" => l.checked_rem(r)?,
+        _ => return None,
+    };
+    Some(value)
+}
+
+impl Expr {
+    /// whether the expression depends on one of the `known` macros
+    pub fn mentions(&self, known: &KnownMacros) -> bool {
+        match self {
+            Expr::Int(..) => false,
+            Expr::Defined(name) | Expr::Ident(name) | Expr::Call(name, _) => {
+                known.contains_key(name)
+            }
+            Expr::Unary(_, e) => e.mentions(known),
+            Expr::Binary(_, l, r) => l.mentions(known) || r.mentions(known),
+            Expr::Conditional(c, t, o) => {
+                c.mentions(known) || t.mentions(known) || o.mentions(known)
+            }
+        }
+    }
+
+    /// replace the `known` macros by their value and fold what can be
+    ///
+    /// in a `boolean` context only the truthiness of the result matters,
+    /// allowing `1 && X` to be simplified to `X`
+    pub fn simplify(&self, known: &KnownMacros, boolean: bool) -> Expr {
+        match self {
+            Expr::Int(..) => self.clone(),
+            Expr::Defined(name) => match known.get(name) {
+                Some(value) => int(value.is_some() as i64),
+                None => self.clone(),
+            },
+            Expr::Ident(name) => match known.get(name) {
+                Some(None) => int(0), // undefined identifiers evaluate to 0
+                Some(Some(value)) => match parse(value) {
+                    Some(value) => value.simplify(&KnownMacros::new(), boolean),
+                    None => self.clone(),
+                },
+                None => self.clone(),
+            },
+            Expr::Call(..) => self.clone(),
+            Expr::Unary(op, operand) => {
+                let operand = operand.simplify(known, *op == "!");
+                let value = as_int(&operand).map(|v| match *op {
+                    "!" => (v == 0) as i64,
+                    "~" => !v,
+                    "-" => v.wrapping_neg(),
+                    _ => v,
+                });
+                match value {
+                    Some(value) => int(value),
+                    None => Expr::Unary(op, Box::new(operand)),
+                }
+            }
+            Expr::Binary(op @ ("&&" | "||"), l, r) => {
+                let l = l.simplify(known, true);
+                let r = r.simplify(known, true);
+                let absorbing = (*op == "||") as i64; // `1 || X` and `0 && X`
+                match (as_int(&l).map(|v| v != 0), as_int(&r).map(|v| v != 0)) {
+                    (Some(lv), Some(rv)) => int(apply_binary(op, lv as i64, rv as i64).unwrap()),
+                    (Some(v), _) | (_, Some(v)) if v as i64 == absorbing => int(absorbing),
+                    (Some(_), None) if boolean => r,
+                    (None, Some(_)) if boolean => l,
+                    _ => Expr::Binary(op, Box::new(l), Box::new(r)),
+                }
+            }
+            Expr::Binary(op, l, r) => {
+                let l = l.simplify(known, false);
+                let r = r.simplify(known, false);
+                match (as_int(&l), as_int(&r)) {
+                    (Some(lv), Some(rv)) => match apply_binary(op, lv, rv) {
+                        Some(value) => int(value),
+                        None => Expr::Binary(op, Box::new(l), Box::new(r)),
+                    },
+                    _ => Expr::Binary(op, Box::new(l), Box::new(r)),
+                }
+            }
+            Expr::Conditional(c, t, o) => {
+                let c = c.simplify(known, true);
+                match as_int(&c) {
+                    Some(0) => o.simplify(known, boolean),
+                    Some(_) => t.simplify(known, boolean),
+                    None => Expr::Conditional(
+                        Box::new(c),
+                        Box::new(t.simplify(known, boolean)),
+                        Box::new(o.simplify(known, boolean)),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// value of the expression if it is fully evaluated
+    pub fn value(&self) -> Option<i64> {
+        as_int(self)
+    }
+
+    fn precedence(&self) -> usize {
+        match self {
+            Expr::Conditional(..) => CONDITIONAL_PRECEDENCE,
+            Expr::Binary(op, ..) => binary_precedence(op),
+            Expr::Unary(..) => UNARY_PRECEDENCE,
+            _ => PRIMARY_PRECEDENCE,
+        }
+    }
+
+    fn print_operand(&self, min_precedence: usize) -> String {
+        if self.precedence() < min_precedence {
+            format!("({self})")
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Int(_, spelling) => write!(f, "{spelling}"),
+            Expr::Defined(name) => write!(f, "defined({name})"),
+            Expr::Ident(name) => write!(f, "{name}"),
+            Expr::Call(name, args) => write!(f, "{name}({args})"),
+            Expr::Unary(op, operand) => {
+                write!(f, "{op}{}", operand.print_operand(UNARY_PRECEDENCE))
+            }
+            Expr::Binary(op, l, r) => {
+                let precedence = binary_precedence(op);
+                let l = l.print_operand(precedence);
+                let r = r.print_operand(precedence + 1); // left associative
+                write!(f, "{l} {op} {r}")
+            }
+            Expr::Conditional(c, t, o) => {
+                let c = c.print_operand(CONDITIONAL_PRECEDENCE + 1);
+                let t = t.print_operand(CONDITIONAL_PRECEDENCE);
+                let o = o.print_operand(CONDITIONAL_PRECEDENCE);
+                write!(f, "{c} ? {t} : {o}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(macros: &[(&str, Option<&str>)]) -> KnownMacros {
+        macros
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.map(String::from)))
+            .collect()
+    }
+
+    fn simplify(input: &str, macros: &[(&str, Option<&str>)]) -> String {
+        let expr = parse(input).unwrap();
+        expr.simplify(&known(macros), true).to_string()
+    }
+
+    #[test]
+    fn prints_with_minimal_parentheses() {
+        let print = |input| parse(input).unwrap().to_string();
+        assert_eq!(print("defined X&&Y"), "defined(X) && Y");
+        assert_eq!(print("(A || B) && C"), "(A || B) && C");
+        assert_eq!(print("A || (B && C)"), "A || B && C");
+        assert_eq!(print("A - (B - C)"), "A - (B - C)");
+        assert_eq!(print("!(A + 1)"), "!(A + 1)");
+        assert_eq!(print("A ? B : C ? D : E"), "A ? B : C ? D : E");
+        assert_eq!(print("__has_include(<x.h>)"), "__has_include(<x.h>)");
+        assert_eq!(print("0x10UL"), "0x10UL");
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert_eq!(parse("A &&"), None);
+        assert_eq!(parse("(A"), None);
+        assert_eq!(parse("defined()"), None);
+        assert_eq!(parse("A B"), None);
+    }
+
+    #[test]
+    fn defined_and_unknown() {
+        let input = "defined(X) && Y";
+        assert_eq!(simplify(input, &[("X", Some("1"))]), "Y");
+        assert_eq!(simplify(input, &[("X", None)]), "0");
+        assert_eq!(simplify(input, &[("Y", Some("1"))]), "defined(X)");
+        assert_eq!(simplify(input, &[("Y", None)]), "0");
+        assert_eq!(simplify(input, &[("X", Some("1")), ("Y", Some("0"))]), "0");
+        assert_eq!(simplify(input, &[("Z", None)]), input);
+    }
+
+    #[test]
+    fn or_is_absorbed() {
+        let input = "defined(X) || Y > 2";
+        assert_eq!(simplify(input, &[("X", Some("1"))]), "1");
+        assert_eq!(simplify(input, &[("X", None)]), "Y > 2");
+        assert_eq!(simplify(input, &[("Y", Some("3"))]), "1");
+        assert_eq!(simplify(input, &[("Y", Some("2"))]), "defined(X)");
+    }
+
+    #[test]
+    fn truthiness_only_in_boolean_context() {
+        // `1 && Y` is 0 or 1, not `Y`, when its value is used
+        assert_eq!(
+            simplify("(defined(X) && Y) + 1", &[("X", Some("1"))]),
+            "(1 && Y) + 1"
+        );
+        assert_eq!(simplify("!(defined(X) && Y)", &[("X", Some("1"))]), "!Y");
+    }
+
+    #[test]
+    fn macro_values() {
+        assert_eq!(simplify("VERSION >= 2", &[("VERSION", Some("3"))]), "1");
+        assert_eq!(simplify("VERSION >= 2", &[("VERSION", None)]), "0");
+        assert_eq!(
+            simplify("VERSION >= 2", &[("VERSION", Some("(1 + 1)"))]),
+            "1"
+        );
+        assert_eq!(simplify("X ? A : B", &[("X", Some("0"))]), "B");
+        assert_eq!(simplify("X / 0", &[("X", Some("1"))]), "1 / 0");
+    }
+
+    #[test]
+    fn mentions_known_macros() {
+        let macros = known(&[("X", None)]);
+        assert!(parse("A || defined X").unwrap().mentions(&macros));
+        assert!(parse("A ? 1 : X(2)").unwrap().mentions(&macros));
+        assert!(!parse("A || defined(Y)").unwrap().mentions(&macros));
+    }
+}
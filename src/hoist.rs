@@ -0,0 +1,180 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{BTreeSet, HashSet};
+
+use crate::directive::{self, Conditionals};
+use crate::expand::{self, strip_comments};
+use crate::process::{EXTERN_C_BEGIN, EXTERN_C_END};
+
+// Moves the system `#include <...>` directives to a sorted block at the top of the output
+// A macro defined before an include may change what the system header declares,
+// so every `#define` or `#undef` other than an include guard is a barrier:
+// the includes following it are left in place
+// So is an `#include "..."` (excluded or quote-only headers), as it may define such macros
+
+lazy_static! {
+    static ref SYSTEM_INCLUDE_REGEX: Regex =
+        Regex::new(r#"^#(?:include|import) <[^>]*>$"#).unwrap();
+    static ref QUOTED_INCLUDE_REGEX: Regex =
+        Regex::new(r#"^#(?:include|import) "[^"]*"$"#).unwrap();
+}
+
+/// hoist the system includes of `lines` before the first line, sorted and deduplicated
+///
+/// includes inside conditionals, implicit `extern "C"` blocks or after a barrier macro are kept in place,
+/// a warning explains why for the latter
+///
+/// `include_guards` are the guards of the inlined files, whose `#ifndef` may have been evaluated
+pub fn hoist_system_includes(
+    lines: Vec<String>,
+    include_guards: &HashSet<String>,
+    warnings: &mut Vec<String>,
+) -> Vec<String> {
+    let mut hoisted = BTreeSet::new();
+    let mut output = Vec::with_capacity(lines.len());
+    let mut barrier: Option<String> = None;
+    let mut kept = 0;
+    let mut conditionals = Conditionals::default();
+    let mut in_extern_c = false;
+    let mut in_comment = false;
+    // macro of the `#ifndef` on the previous line
+    let mut tested: Option<String> = None;
+
+    for line in lines {
+        let was_in_comment = in_comment;
+        let code = strip_comments(&line, &mut in_comment);
+        if was_in_comment {
+            output.push(line);
+            continue;
+        }
+
+        let follows = |block: &[&str]| output.last().is_some_and(|last| last == block[0]);
+        if line == EXTERN_C_BEGIN[1] && follows(EXTERN_C_BEGIN) {
+            in_extern_c = true;
+        } else if line == EXTERN_C_END[1] && follows(EXTERN_C_END) {
+            in_extern_c = false;
+        }
+
+        if SYSTEM_INCLUDE_REGEX.is_match(&line) && !conditionals.is_conditional() && !in_extern_c {
+            if barrier.is_none() {
+                hoisted.insert(line);
+                // keeps the other lines in place, along with their locations
+                output.push(String::new());
+                continue;
+            }
+            kept += 1;
+        } else if QUOTED_INCLUDE_REGEX.is_match(&line) {
+            barrier.get_or_insert_with(|| line.clone());
+        } else if let Some(conditional) = directive::conditional(&code) {
+            conditionals.feed(conditional, &code, |name| {
+                include_guards.contains(name) || expand::is_once_guard(name)
+            });
+        } else if let Some((define, name)) = directive::macro_definition(&code) {
+            let guard =
+                define && (include_guards.contains(name) || tested.as_deref() == Some(name));
+            if barrier.is_none() && !guard {
+                barrier = Some(code.trim().to_string());
+            }
+        }
+        if !code.trim().is_empty() {
+            tested = directive::guard_name(&code).map(String::from);
+        }
+        output.push(line);
+    }
+
+    if let Some(barrier) = barrier.filter(|_| kept > 0) {
+        warnings.push(format!(
+            "{kept} system include(s) not hoisted: they follow `{barrier}`, \
+             which may change what system headers declare"
+        ));
+    }
+
+    let mut hoisted: Vec<String> = hoisted.into_iter().collect();
+    if !hoisted.is_empty() {
+        hoisted.push(String::new());
+    }
+    hoisted.extend(output);
+    hoisted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hoist(input: &str, guards: &[&str]) -> Vec<String> {
+        let lines = input.lines().map(String::from).collect();
+        let guards = guards.iter().map(|guard| guard.to_string()).collect();
+        hoist_system_includes(lines, &guards, &mut Vec::new())
+    }
+
+    #[test]
+    fn stops_at_any_macro_definition() {
+        let output = hoist(
+            "#include <b.h>\n#define MYLIB_CONFIG 1\n#include <a.h>",
+            &[],
+        );
+        assert_eq!(
+            output,
+            [
+                "#include <b.h>",
+                "",
+                "",
+                "#define MYLIB_CONFIG 1",
+                "#include <a.h>"
+            ]
+        );
+        let output = hoist("#undef GLM_FORCE_RADIANS\n#include <a.h>", &[]);
+        assert_eq!(output, ["#undef GLM_FORCE_RADIANS", "#include <a.h>"]);
+    }
+
+    #[test]
+    fn quoted_includes_are_barriers() {
+        // `config.h` is kept external and may define `_GNU_SOURCE`
+        let mut warnings = Vec::new();
+        let lines = [
+            "#include <string.h>",
+            "#include \"config.h\"",
+            "#include <stdio.h>",
+        ];
+        let lines = lines.map(String::from).to_vec();
+        let output = hoist_system_includes(lines, &HashSet::new(), &mut warnings);
+        assert_eq!(
+            output,
+            [
+                "#include <string.h>",
+                "",
+                "",
+                "#include \"config.h\"",
+                "#include <stdio.h>"
+            ]
+        );
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn include_guards_are_not_barriers() {
+        // evaluated by the preprocessor, only the `#define` is left
+        let output = hoist("#define LIB_H\n#include <b.h>\n#include <a.h>", &["LIB_H"]);
+        assert_eq!(
+            output,
+            [
+                "#include <a.h>",
+                "#include <b.h>",
+                "",
+                "#define LIB_H",
+                "",
+                ""
+            ]
+        );
+        // kept as written, the includes inside the conditional stay
+        let output = hoist("#ifndef LIB_H\n#define LIB_H\n#endif\n#include <a.h>", &[]);
+        assert_eq!(output[0], "#include <a.h>");
+        // kept as written by --preserve-conditionals, the guard doesn't make them conditional
+        let output = hoist(
+            "#ifndef LIB_H\n#define LIB_H\n#include <b.h>\n#ifdef X\n#include <a.h>\n#endif\n#endif",
+            &["LIB_H"],
+        );
+        assert_eq!(output[0], "#include <b.h>");
+        assert!(output.contains(&String::from("#include <a.h>")));
+    }
+}
@@ -1,7 +1,6 @@
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
-
-use lazy_static::lazy_static;
-use regex::Regex;
+use std::str::Chars;
 
 // Documentation: https://gcc.gnu.org/onlinedocs/cpp/Preprocessor-Output.html
 
@@ -20,6 +19,8 @@ pub struct IncludeState {
     pub status: FlagStatus,
     pub system_header: bool,
     pub extern_c: bool,
+    // flags this tool doesn't know about, in order
+    pub unknown_flags: Vec<u32>,
 }
 
 impl IncludeState {
@@ -28,6 +29,7 @@ impl IncludeState {
             status: FlagStatus::NotSet,
             system_header: false,
             extern_c: false,
+            unknown_flags: Vec::new(),
         }
     }
 
@@ -41,51 +43,126 @@ impl IncludeState {
     }
 }
 
+/// file a linemarker points to
+#[derive(Clone, PartialEq)]
+pub enum LineFile {
+    Path(PathBuf), // canonicalized existing file
+    Missing,       // file name that doesn't exist on disk
+    BuiltIn,       // `<built-in>`
+    CommandLine,   // `<command-line>`
+    Stdin,         // `<stdin>`
+    Pseudo,        // any other `<...>` name
+}
+
 pub struct IncludeDirective {
     pub linenum: u32,
-    pub filename: String,
-    pub absolute_path: Option<PathBuf>,
+    pub file: LineFile,
     pub state: IncludeState,
 }
 
-fn make_include_output_regex() -> Regex {
-    let hash_start = r"^#";
-    let sep = r"\s*";
-    let line_regex = r"(\d+)";
-    let filename_regex = r#""([^"]*)""#;
+impl IncludeDirective {
+    pub fn absolute_path(&self) -> Option<&PathBuf> {
+        match &self.file {
+            LineFile::Path(path) => Some(path),
+            _ => None,
+        }
+    }
+}
 
-    let re = format!(r"{hash_start}{sep}{line_regex}{sep}{filename_regex}");
-    Regex::new(re.as_str()).unwrap()
+fn skip_spaces(chars: &mut Peekable<Chars>) -> bool {
+    let mut skipped = false;
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {
+        skipped = true;
+    }
+    skipped
 }
 
-fn make_flags_regex() -> Regex {
-    let flag_regex = r"\s*(\d+)";
-    Regex::new(flag_regex).unwrap()
+fn number(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+    digits.parse().ok()
 }
 
-lazy_static! {
-    static ref INCLUDE_OUTPUT_REGEX: Regex = make_include_output_regex();
-    static ref FLAGS_REGEX: Regex = make_flags_regex();
+// decodes the C string escapes GCC writes file names with, up to the closing `"`
+fn string_literal(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0; 4];
+    loop {
+        let c = chars.next()?;
+        let escaped = match c {
+            '"' => return Some(String::from_utf8_lossy(&bytes).into_owned()),
+            '\\' => chars.next()?,
+            c => {
+                bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                continue;
+            }
+        };
+        let byte = match escaped {
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'v' => 0x0b,
+            '0'..='7' => {
+                let mut value = escaped.to_digit(8)?;
+                for _ in 0..2 {
+                    match chars.next_if(|c| c.is_digit(8)) {
+                        Some(digit) => value = value * 8 + digit.to_digit(8)?,
+                        None => break,
+                    }
+                }
+                value as u8
+            }
+            'x' => {
+                let mut value = 0u32;
+                while let Some(digit) = chars.next_if(char::is_ascii_hexdigit) {
+                    value = (value * 16 + digit.to_digit(16)?) & 0xff;
+                }
+                value as u8
+            }
+            // `\\`, `\"`, `\'`, `\?` and unknown escapes stand for themselves
+            c => {
+                bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                continue;
+            }
+        };
+        bytes.push(byte);
+    }
 }
 
-const BAD_NUMBER_PARSE: &str = "parsed as number by regex but not a number";
+fn line_file(filename: &str) -> LineFile {
+    match filename {
+        "<built-in>" => LineFile::BuiltIn,
+        "<command-line>" => LineFile::CommandLine,
+        "<stdin>" => LineFile::Stdin,
+        name if name.starts_with('<') && name.ends_with('>') => LineFile::Pseudo,
+        name => match Path::new(name).canonicalize() {
+            Ok(path) => LineFile::Path(path),
+            Err(_) => LineFile::Missing,
+        },
+    }
+}
 
+/// parses a `# linenum "filename" flags...` linemarker
 pub fn try_parse(line: &str) -> Option<IncludeDirective> {
-    let Some(captures) = INCLUDE_OUTPUT_REGEX.captures(line) else {
+    let mut chars = line.strip_prefix('#')?.chars().peekable();
+    skip_spaces(&mut chars);
+    let linenum = number(&mut chars)?;
+    if !skip_spaces(&mut chars) || chars.next() != Some('"') {
         return None;
-    };
-    let (full, [linenum, filename]) = captures.extract();
-    let linenum = linenum.parse::<u32>().expect(BAD_NUMBER_PARSE);
-    let end = full.len();
-    let flags_substr = &line[end..];
+    }
+    let filename = string_literal(&mut chars)?;
 
     let mut state = IncludeState::new();
-
-    FLAGS_REGEX.captures_iter(flags_substr).for_each(|c| {
-        let (_, [flag_str]) = c.extract();
-        let flag_number = flag_str.parse::<u32>().expect(BAD_NUMBER_PARSE);
-
-        match flag_number {
+    while skip_spaces(&mut chars) {
+        let Some(flag) = number(&mut chars) else {
+            break; // trailing spaces
+        };
+        match flag {
             flag if flag == FlagStatus::Open as u32 => {
                 state.status = FlagStatus::Open;
             }
@@ -98,16 +175,102 @@ pub fn try_parse(line: &str) -> Option<IncludeDirective> {
             flag if flag == FlagOpt::ExternC as u32 => {
                 state.extern_c = true;
             }
-            _ => {}
+            flag => state.unknown_flags.push(flag),
         }
-    });
-    let absolute_path = Path::new(filename).canonicalize().ok();
-    let filename = String::from(filename);
+    }
+    if chars.next().is_some() {
+        return None; // not a linemarker
+    }
 
-    return Some(IncludeDirective {
+    Some(IncludeDirective {
         linenum,
-        filename,
-        absolute_path,
+        file: line_file(&filename),
         state,
-    });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> IncludeDirective {
+        try_parse(line).unwrap_or_else(|| panic!("`{line}` is a linemarker"))
+    }
+
+    #[test]
+    fn escaped_quotes_and_backslashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a \"quoted\" \\ name.h");
+        std::fs::write(&path, "").unwrap();
+        let escaped = path
+            .display()
+            .to_string()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+
+        let marker = parse(&format!("# 12 \"{escaped}\""));
+        assert_eq!(marker.linenum, 12);
+        assert_eq!(marker.absolute_path(), Some(&path.canonicalize().unwrap()));
+
+        // octal and hexadecimal escapes
+        let octal = escaped.replace("name", "\\156\\x61me");
+        assert!(parse(&format!("# 1 \"{octal}\"")).absolute_path().is_some());
+    }
+
+    #[test]
+    fn pseudo_files() {
+        let file = |line| parse(line).file;
+        assert!(matches!(file("# 0 \"<built-in>\""), LineFile::BuiltIn));
+        assert!(matches!(
+            file("# 0 \"<command-line>\""),
+            LineFile::CommandLine
+        ));
+        assert!(matches!(file("# 1 \"<stdin>\""), LineFile::Stdin));
+        assert!(matches!(file("# 1 \"<scratch space>\""), LineFile::Pseudo));
+        assert!(matches!(
+            file("# 1 \"/single/header/missing.h\""),
+            LineFile::Missing
+        ));
+        assert_eq!(parse("# 1 \"<built-in>\"").absolute_path(), None);
+    }
+
+    #[test]
+    fn flags() {
+        let state = parse("# 1 \"/usr/include/stdio.h\" 1 3 4").state;
+        assert!(matches!(state.status, FlagStatus::Open));
+        assert!(state.system_header && state.extern_c);
+        assert!(state.unknown_flags.is_empty());
+        assert!(!state.ignorable());
+
+        let state = parse("# 30 \"main.h\" 2").state;
+        assert!(matches!(state.status, FlagStatus::Close));
+        assert!(!state.system_header && !state.extern_c);
+
+        let state = parse("#  7 \"main.h\"   5 3 ").state;
+        assert!(matches!(state.status, FlagStatus::NotSet));
+        assert!(state.system_header);
+        assert_eq!(state.unknown_flags, [5]);
+        assert!(state.ignorable());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in [
+            "",
+            "#",
+            "#define X 1",
+            "#line 1 \"main.h\"",
+            "# \"main.h\"",
+            "# 1",
+            "# 1main.h",
+            "# 1 main.h",
+            "# 1 \"main.h",
+            "# 1 \"main.h\"2",
+            "# 1 \"main.h\" 1 x",
+            "# 1 \"main.h\" // comment",
+            " # 1 \"main.h\"",
+        ] {
+            assert!(try_parse(line).is_none(), "`{line}` isn't a linemarker");
+        }
+    }
 }
@@ -0,0 +1,36 @@
+//! Convert C/C++ files into portable single-header files.
+//!
+//! ```no_run
+//! use single_header::{Protection, SingleHeader};
+//!
+//! let header = SingleHeader::new("include/lib.hpp")
+//!     .protection(Protection::Once)
+//!     .cpp_opt("-Iinclude")
+//!     .generate()?;
+//! # Ok::<(), single_header::Error>(())
+//! ```
+
+mod amalgamate;
+mod args;
+mod cmake;
+mod directive;
+mod error;
+mod expand;
+mod expr;
+mod hoist;
+mod include_line;
+mod line_zero;
+mod path_filter;
+mod probe;
+mod process;
+mod rewrite;
+mod single_header;
+mod source_map;
+mod system_paths;
+mod unifdef;
+mod utils;
+
+pub use args::{Dialect, Lang, Preprocessor, Protection};
+pub use error::{Error, Result};
+pub use single_header::SingleHeader;
+pub use source_map::{Mapping, SourceMap};
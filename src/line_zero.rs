@@ -1,7 +1,11 @@
-use crate::include_line::{FlagStatus, IncludeDirective};
+use crate::include_line::{FlagStatus, IncludeDirective, LineFile};
 
 pub struct LineZeroState {
+    // file of the first linemarker outside of the preamble
+    main_file: Option<LineFile>,
     in_line_zero: bool,
+    // files opened from the preamble and not closed yet
+    depth: usize,
 }
 
 pub struct Skip(pub bool);
@@ -9,29 +13,164 @@ pub struct Skip(pub bool);
 impl LineZeroState {
     pub fn new() -> Self {
         LineZeroState {
+            main_file: None,
             in_line_zero: false,
+            depth: 0,
         }
     }
 
     pub fn ignore_line(&self) -> bool {
-        return self.in_line_zero;
+        self.in_line_zero
     }
 
-    // handle builtin `0` lines produced by the processor
+    // handle the builtin and command line preamble produced by the processor:
+    // `# 0 "<built-in>"` ... `# 0 "<command-line>" 2` for GCC 10 and later,
+    // `# 1 "<built-in>"` ... `# 1 "main.h"` for older GCC and `# 1 "main.h" 2` for clang
+    // it ends when the main file comes back, whatever the line number and flags
     pub fn feed(&mut self, include_info: &IncludeDirective) -> Skip {
-        if include_info.linenum != 0 && !self.in_line_zero {
-            return Skip(false);
+        let preamble_file = matches!(
+            include_info.file,
+            LineFile::BuiltIn | LineFile::CommandLine | LineFile::Pseudo
+        );
+        if !self.in_line_zero {
+            if !preamble_file && self.main_file.is_none() {
+                self.main_file = Some(include_info.file.clone());
+            }
+            self.in_line_zero = preamble_file;
+            self.depth = 0;
+            return Skip(preamble_file);
         }
 
-        // only know it's the last builtin line 0 when `# 0 "..." 2` is found
-        // any other line starting with `# 0 "..."` indicates we are still in a built-in section
-        // and should continue ignoring all lines
+        let status = &include_info.state.status;
+        let returns = match &self.main_file {
+            Some(main_file) => *main_file == include_info.file,
+            None => !matches!(status, FlagStatus::Open),
+        };
+        match status {
+            FlagStatus::Close if self.depth > 0 => self.depth -= 1,
+            _ if preamble_file => {}
+            _ if self.depth == 0 && returns => {
+                self.in_line_zero = false;
+                return Skip(false);
+            }
+            FlagStatus::Open => self.depth += 1,
+            _ => {}
+        }
+        Skip(true)
+    }
+}
 
-        if !self.in_line_zero {
-            self.in_line_zero = true;
-        } else if matches!(include_info.state.status, FlagStatus::Close) {
-            self.in_line_zero = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::include_line;
+
+    // lines kept from `output`, `{dir}` being a folder holding `main.h`, `pre.h` and `sub.h`
+    fn kept(output: &str) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["main.h", "pre.h", "sub.h"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        let output = output.replace("{dir}", &dir.path().display().to_string());
+
+        let mut state = LineZeroState::new();
+        let mut kept = Vec::new();
+        for line in output.lines() {
+            match include_line::try_parse(line) {
+                Some(marker) => {
+                    state.feed(&marker);
+                }
+                None if !state.ignore_line() => kept.push(String::from(line)),
+                None => {}
+            }
         }
-        return Skip(true);
+        kept
+    }
+
+    #[test]
+    fn gcc_zero_preamble() {
+        let output = r#"# 0 "{dir}/main.h"
+# 0 "<built-in>"
+#define __STDC__ 1
+# 0 "<built-in>"
+#define __GNUC__ 12
+# 0 "<command-line>"
+# 1 "/usr/include/stdc-predef.h" 1 3 4
+#define _STDC_PREDEF_H 1
+# 0 "<command-line>" 2
+# 1 "{dir}/pre.h" 1
+int pre;
+# 0 "<command-line>" 2
+# 1 "{dir}/main.h"
+int main_h;
+# 1 "{dir}/sub.h" 1
+int sub;
+# 2 "{dir}/main.h" 2
+int end;"#;
+        assert_eq!(kept(output), ["int main_h;", "int sub;", "int end;"]);
+    }
+
+    #[test]
+    fn gcc_zero_preamble_without_predefs() {
+        // `-nostdinc`: nothing is opened from the command line
+        let output = r#"# 0 "{dir}/main.h"
+# 0 "<built-in>"
+#define __STDC__ 1
+# 0 "<command-line>"
+#define CONFIG 1
+# 1 "{dir}/main.h"
+# 1 "{dir}/sub.h" 1
+int sub;
+# 2 "{dir}/main.h" 2"#;
+        assert_eq!(kept(output), ["int sub;"]);
+    }
+
+    #[test]
+    fn gcc_one_preamble() {
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "<built-in>"
+#define __STDC__ 1
+# 1 "<command-line>"
+# 31 "<command-line>"
+# 1 "/usr/include/stdc-predef.h" 1 3 4
+#define _STDC_PREDEF_H 1
+# 32 "<command-line>" 2
+# 1 "{dir}/pre.h" 1
+int pre;
+# 33 "<command-line>" 2
+# 1 "{dir}/main.h"
+int main_h;"#;
+        assert_eq!(kept(output), ["int main_h;"]);
+    }
+
+    #[test]
+    fn clang_preamble() {
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "<built-in>" 1
+# 1 "<built-in>" 3
+#define __clang__ 1
+# 380 "<built-in>" 3
+# 1 "<command line>" 1
+#define CONFIG 1
+# 1 "<built-in>" 2
+# 1 "{dir}/pre.h" 1
+int pre;
+# 2 "<built-in>" 2
+# 1 "{dir}/main.h" 2
+int main_h;
+# 1 "{dir}/sub.h" 1
+int sub;
+# 2 "{dir}/main.h" 2"#;
+        assert_eq!(kept(output), ["int main_h;", "int sub;"]);
+    }
+
+    #[test]
+    fn no_preamble() {
+        let output = r#"# 1 "{dir}/main.h"
+int main_h;
+# 1 "{dir}/sub.h" 1
+int sub;
+# 2 "{dir}/main.h" 2"#;
+        assert_eq!(kept(output), ["int main_h;", "int sub;"]);
     }
 }
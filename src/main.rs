@@ -1,18 +1,10 @@
-mod args;
-mod cmake;
-mod include_line;
-mod inline_paths;
-mod line_zero;
-mod process;
-mod system_paths;
-mod utils;
-
-use anyhow::{Context, Result};
-use args::{Lang, Preprocessor, Protection};
-use clap::{ArgAction, Parser};
-use process::process_lines;
-use std::process::Command;
-use utils::LazyForEach;
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Parser, Subcommand};
+use single_header::{Dialect, Error, Lang, Preprocessor, Protection, SingleHeader, SourceMap};
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -25,16 +17,55 @@ Runs C/C++ <preprocessor> on <FILE>
 Preventing builtin macros and macro expansion (but #if/#ifdef will be executed).
 It then undoes the `#include` expension of all the system headers,
 replacing them with an `#include <...>` directive that will be portable.
-"#
+"#,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Ops {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
-        default_value = Preprocessor::Cpp.as_str(),
         short = 'p',
         long = "preprocessor",
-        value_enum
+        value_enum,
+        help = "`builtin` needs no executable and implies --preserve-conditionals, `-D`, `-U` and `-include` options are ignored [default: $CXX for c++, $CPP / $CC for c, $OBJC / $CC for objective-c or $OBJCXX / $CXX for objective-c++, if set, run through their launcher (`ccache g++`), else the first of cpp, gcc and clang found]"
+    )]
+    preprocessor: Option<Preprocessor>,
+
+    #[arg(
+        long = "preprocessor-path",
+        name = "EXE",
+        conflicts_with = "preprocessor",
+        help = "any preprocessor or compiler executable (`gcc-13`, `clang-18`, `arm-none-eabi-gcc`, ...)"
+    )]
+    preprocessor_path: Option<PathBuf>,
+
+    #[arg(
+        long = "dialect",
+        value_enum,
+        help = "command line conventions of the preprocessor [default: the one its `--version` reports, else clang if its name contains `clang`, else gnu]"
+    )]
+    dialect: Option<Dialect>,
+
+    #[arg(
+        long = "target",
+        name = "TRIPLE",
+        help = "target to generate the single header for, selects `<TRIPLE>-<PREPROCESSOR>` with the gnu dialect instead of the environment's compiler"
+    )]
+    target: Option<String>,
+
+    #[arg(
+        long = "sysroot",
+        help = "root of the target's headers, given to the preprocessor and never part of an emitted `#include`"
     )]
-    preprocessor: Preprocessor,
+    sysroot: Option<PathBuf>,
+
+    #[arg(
+        long = "ignore-env",
+        help = "ignore the CC / CXX / CPP / OBJC / OBJCXX and CPPFLAGS environment variables"
+    )]
+    ignore_env: bool,
 
     #[arg(
         long = "cmake",
@@ -47,10 +78,86 @@ struct Ops {
         long="inline",
         name="INLINE_PATH",
         action = ArgAction::Append,
-        help="path / file that must allways be `#include` expanded (can be provided multiple times)"
+        help="path, glob (`third_party/**/*.h`) or include name (`fmt/*`, `nlohmann/json.hpp`) of headers that must allways be `#include` expanded (can be provided multiple times)"
     )]
     inline_paths: Vec<String>,
 
+    #[arg(
+        short = 'e',
+        long = "exclude",
+        name = "EXCLUDE_PATH",
+        action = ArgAction::Append,
+        help = "path, glob or include name of local headers that must not be `#include` expanded, an `#include \"...\"` is kept instead (can be provided multiple times)"
+    )]
+    exclude_paths: Vec<String>,
+
+    #[arg(
+        long = "exclude-base",
+        help = "folder the `#include` of excluded headers are relative to [default: folder of the first <FILES>]"
+    )]
+    exclude_base: Option<std::path::PathBuf>,
+
+    #[arg(
+        long = "implementation",
+        name = "SOURCE",
+        action = ArgAction::Append,
+        help = "c/c++ source whose content is only compiled when `<NAME>_IMPLEMENTATION` is defined, STB-style (can be provided multiple times)"
+    )]
+    implementation: Vec<String>,
+
+    #[arg(
+        long = "preserve-conditionals",
+        help = "keep `#if/#ifdef/#elif/#else/#endif` of local headers as written instead of evaluating them"
+    )]
+    preserve_conditionals: bool,
+
+    #[arg(
+        long = "eval",
+        name = "MACRO[=VALUE]",
+        action = ArgAction::Append,
+        help = "evaluate the conditionals depending on MACRO as if it was defined (to 1 by default), others are kept as written, implies --preserve-conditionals (can be provided multiple times)"
+    )]
+    eval_defined: Vec<String>,
+
+    #[arg(
+        long = "eval-undef",
+        name = "MACRO",
+        action = ArgAction::Append,
+        help = "evaluate the conditionals depending on MACRO as if it was undefined, implies --preserve-conditionals (can be provided multiple times)"
+    )]
+    eval_undefined: Vec<String>,
+
+    #[arg(
+        long = "dedupe-system-includes",
+        help = "only keep the first `#include <...>` of each system header"
+    )]
+    dedupe_system_includes: bool,
+
+    #[arg(
+        long = "hoist-system-includes",
+        help = "move the system `#include <...>` to a sorted block at the top, up to the first `#define`, `#undef` other than an include guard or `#include \"...\"`, as it may change them"
+    )]
+    hoist_system_includes: bool,
+
+    #[arg(
+        long = "line-directives",
+        help = "emit `#line` directives so diagnostics and `__FILE__`/`__LINE__` point back to the original files"
+    )]
+    line_directives: bool,
+
+    #[arg(
+        long = "source-map",
+        requires = "output",
+        help = "write a source map of <OUTPUT> to <OUTPUT>.map, to be read by the `locate` command"
+    )]
+    source_map: bool,
+
+    #[arg(
+        long = "line-root",
+        help = "folder the `#line` directive and source map paths are relative to [default: current directory]"
+    )]
+    line_root: Option<std::path::PathBuf>,
+
     #[arg(default_value = Lang::Cpp.as_str(), short = 'x', long = "lang", value_enum)]
     lang: Lang,
 
@@ -62,8 +169,18 @@ struct Ops {
     )]
     protection: Protection,
 
-    #[arg(help = "path to c/c++ header file")]
-    file: String,
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "write the single header to <OUTPUT> instead of stdout, only replacing it on success and if its content changed"
+    )]
+    output: Option<std::path::PathBuf>,
+
+    #[arg(
+        required = true,
+        help = "path to c/c++ header file, several files or folders are amalgamated into one single header (folders contribute the headers no other header includes)"
+    )]
+    files: Vec<String>,
 
     #[arg(
         help = r"additional parameters for the preprocessor",
@@ -73,47 +190,193 @@ struct Ops {
     cpp_opts: Vec<String>,
 }
 
-fn base_args(required: Vec<&'static str>, lang: Lang) -> Vec<&'static str> {
-    let base_args = vec![
-        "-x",
-        lang.as_str(),
-        "-fdirectives-only", // prevent macro expansion
-    ];
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "print the original location of a line of a generated single header")]
+    Locate {
+        #[arg(
+            name = "OUTPUT:LINE",
+            value_parser = parse_location,
+            help = "line of a single header generated with --source-map"
+        )]
+        location: (PathBuf, usize),
+    },
+}
+
+fn parse_location(location: &str) -> Result<(PathBuf, usize), String> {
+    let (output, line) = location
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected <OUTPUT>:<LINE>, got `{location}`"))?;
+    let line = line
+        .parse()
+        .map_err(|_| format!("invalid line number `{line}`"))?;
+    Ok((PathBuf::from(output), line))
+}
+
+// preprocessor command of the `CC`-like environment variables, and `CPPFLAGS`
+fn environment(lang: &Lang) -> (Option<Vec<String>>, Vec<String>) {
+    let split = |name: &str| {
+        let value = env::var(name).ok()?;
+        shlex::split(&value).filter(|words| !words.is_empty())
+    };
+    let names: &[&str] = match lang {
+        Lang::C => &["CPP", "CC"],
+        Lang::Cpp => &["CXX"],
+        Lang::ObjC => &["OBJC", "CC"],
+        Lang::ObjCpp => &["OBJCXX", "CXX"],
+    };
+    let command = names.iter().find_map(|name| split(name));
+    (command, split("CPPFLAGS").unwrap_or_default())
+}
 
-    utils::merge(required, base_args)
+// launcher (`ccache`, `env VAR=value`, ...), compiler and options of an environment command:
+// the compiler is the last word before the first option
+fn split_command(command: &[String]) -> (&[String], &String, &[String]) {
+    let options = command
+        .iter()
+        .position(|word| word.starts_with('-'))
+        .unwrap_or(command.len())
+        .max(1);
+    let (words, options) = command.split_at(options);
+    let (compiler, launcher) = words.split_last().expect("the command isn't empty");
+    (launcher, compiler, options)
+}
+
+fn locate((output, line): (PathBuf, usize)) -> Result<()> {
+    let source_map = SourceMap::read(&SourceMap::sidecar_path(&output))?;
+    let (source, source_line) = source_map.locate(line).ok_or_else(|| {
+        anyhow!(
+            "line {line} of {} doesn't come from a source file",
+            output.display()
+        )
+    })?;
+    println!("{source}:{source_line}");
+    Ok(())
 }
 
 // #[unix_sigpipe = "sig_dfl"] // TODO: enable once it's stable: https://github.com/rust-lang/rust/issues/97889
-fn main() -> Result<()> {
-    let ops = Ops::parse();
+fn main() -> ExitCode {
+    let ops = Ops::parse(); // exits with code 2 on usage errors
 
-    let preprocessor = ops.preprocessor.as_str();
+    match run(ops) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            let code = err.downcast_ref::<Error>().map_or(1, Error::exit_code);
+            ExitCode::from(code)
+        }
+    }
+}
 
-    which::which(preprocessor)
-        .with_context(|| format!("Failed to find preprocessor `{preprocessor}` in PATH"))?;
+fn run(ops: Ops) -> Result<()> {
+    if let Some(Command::Locate { location }) = ops.command {
+        return locate(location);
+    }
 
-    let base_preprocessor_args = base_args(ops.preprocessor.required_args(), ops.lang);
+    let (file, other_files) = ops.files.split_first().expect("FILE is required");
 
-    let cmake_opts = cmake::cmake_options(ops.cmake, &ops.file)?;
-    let extra_cpp_opts = utils::merge(cmake_opts, ops.cpp_opts);
+    let mut single_header = SingleHeader::new(file).inputs(other_files);
 
-    let search_paths =
-        system_paths::SearchPaths::new(preprocessor, &base_preprocessor_args, &extra_cpp_opts)?;
+    if !ops.ignore_env {
+        let (command, cpp_flags) = environment(&ops.lang);
+        // the compiler of the environment targets the host, `--target` picks its own
+        let default_preprocessor =
+            ops.preprocessor.is_none() && ops.preprocessor_path.is_none() && ops.target.is_none();
+        if let Some(command) = command.filter(|_| default_preprocessor) {
+            let (launcher, compiler, options) = split_command(&command);
+            single_header = single_header
+                .launcher(launcher)
+                .preprocessor_path(compiler)
+                .cpp_opts(options);
+        }
+        single_header = single_header.cpp_opts(cpp_flags);
+    }
+    if let Some(preprocessor) = ops.preprocessor {
+        single_header = single_header.preprocessor(preprocessor);
+    }
+    if let Some(path) = ops.preprocessor_path {
+        single_header = single_header.preprocessor_path(path);
+    }
+    if let Some(dialect) = ops.dialect {
+        single_header = single_header.dialect(dialect);
+    }
+    if let Some(target) = ops.target {
+        single_header = single_header.target(target);
+    }
+    if let Some(sysroot) = ops.sysroot {
+        single_header = single_header.sysroot(sysroot);
+    }
 
-    let inline_paths = inline_paths::InlinePaths::new(ops.inline_paths);
+    single_header = single_header
+        .on_warning(|warning| eprintln!("warning: {warning}"))
+        .lang(ops.lang)
+        .protection(ops.protection)
+        .inline_paths(ops.inline_paths)
+        .exclude_paths(ops.exclude_paths)
+        .implementation_sources(ops.implementation)
+        .preserve_conditionals(ops.preserve_conditionals)
+        .dedupe_system_includes(ops.dedupe_system_includes)
+        .hoist_system_includes(ops.hoist_system_includes)
+        .line_directives(ops.line_directives)
+        .source_map(ops.source_map)
+        .cpp_opts(ops.cpp_opts);
 
-    let mut command = Command::new(preprocessor);
+    for define in ops.eval_defined {
+        let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+        single_header = single_header.eval_defined(name, value);
+    }
+    for name in ops.eval_undefined {
+        single_header = single_header.eval_undefined(name);
+    }
 
-    command
-        .args(&base_preprocessor_args)
-        .arg(&ops.file)
-        .args(extra_cpp_opts);
+    if let Some(line_root) = ops.line_root {
+        single_header = single_header.line_root(line_root);
+    }
 
-    let lines = utils::stdout_command("C preprocessor", command)?;
-    let output = process_lines(lines, search_paths, inline_paths);
+    if let Some(exclude_base) = ops.exclude_base {
+        single_header = single_header.exclude_base(exclude_base);
+    }
 
-    let lazy_print_lines = output.lazy_for_each(|line| println!("{line}"));
-    ops.protection.protect(lazy_print_lines, ops.file);
+    if let Some(cmake) = ops.cmake {
+        single_header = single_header.cmake(cmake);
+    }
+
+    match ops.output {
+        Some(output) => single_header.write_to_file(output)?,
+        None => {
+            let mut stdout = io::stdout().lock();
+            single_header.write_to(&mut stdout)?;
+            stdout.flush()?;
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_launchers() {
+        let split = |command: &str| {
+            let words = shlex::split(command).unwrap();
+            let (launcher, compiler, options) = split_command(&words);
+            (launcher.join(" "), compiler.clone(), options.join(" "))
+        };
+        let owned = |parts: (&str, &str, &str)| {
+            (
+                String::from(parts.0),
+                String::from(parts.1),
+                String::from(parts.2),
+            )
+        };
+        assert_eq!(split("g++"), owned(("", "g++", "")));
+        assert_eq!(split("g++ -m32"), owned(("", "g++", "-m32")));
+        assert_eq!(split("ccache g++ -m32"), owned(("ccache", "g++", "-m32")));
+        assert_eq!(
+            split("env CCACHE_DIR=/tmp sccache clang++"),
+            owned(("env CCACHE_DIR=/tmp sccache", "clang++", ""))
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::system_paths::SearchPaths;
+
+/// set of files and folders, a folder containing all the files beneath it
+///
+/// each pattern is either:
+/// - a path to an existing file or folder
+/// - a glob matched against absolute paths, relative ones being relative to the current directory
+/// - an include name (`nlohmann/json.hpp`) or an include name glob (`fmt/*`),
+///   matched against paths relative to the search paths
+///
+/// matching is done on whole path components, so `src/foo` doesn't match `src/foobar.h`
+#[derive(Default)]
+pub struct PathFilter {
+    paths: Vec<PathBuf>,
+    globs: Vec<GlobMatcher>,
+    include_names: Vec<GlobMatcher>,
+    // search paths include names are relative to
+    search_dirs: Vec<PathBuf>,
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '{'])
+}
+
+fn glob(pattern: &str, original: &str) -> Result<GlobMatcher> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true) // `*` doesn't match `/`
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|source| Error::InvalidPattern {
+            pattern: String::from(original),
+            source,
+        })
+}
+
+impl PathFilter {
+    pub fn new(patterns: &[String], search_paths: &SearchPaths) -> Result<Self> {
+        let search_dirs: Vec<PathBuf> = search_paths
+            .quote_paths()
+            .iter()
+            .chain(search_paths.ordered_paths())
+            .cloned()
+            .collect();
+        let mut filter = PathFilter {
+            search_dirs,
+            ..Default::default()
+        };
+        if patterns.is_empty() {
+            return Ok(filter);
+        }
+        let current_dir = std::env::current_dir()
+            .and_then(|dir| dir.canonicalize())
+            .map_err(Error::CurrentDir)?;
+
+        for pattern in patterns {
+            if is_glob(pattern) {
+                let absolute = if Path::new(pattern).is_absolute() {
+                    pattern.clone()
+                } else {
+                    let base = globset::escape(&current_dir.display().to_string());
+                    format!("{base}/{pattern}")
+                };
+                filter.globs.push(glob(&absolute, pattern)?);
+                filter.include_names.push(glob(pattern, pattern)?);
+            } else if let Ok(path) = Path::new(pattern).canonicalize() {
+                filter.paths.push(path);
+            } else if filter
+                .search_dirs
+                .iter()
+                .any(|dir| dir.join(pattern).exists())
+            {
+                let name = Glob::new(&globset::escape(pattern)).expect("escaped pattern is valid");
+                filter.include_names.push(name.compile_matcher());
+            } else {
+                return Err(Error::UnknownPath(pattern.clone()));
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        if self.paths.iter().any(|prefix| path.starts_with(prefix)) {
+            return true;
+        }
+        // a matching folder contains the path
+        let matches = |globs: &[GlobMatcher], path: &Path| {
+            path.ancestors()
+                .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| globs.iter().any(|glob| glob.is_match(ancestor)))
+        };
+        if matches(&self.globs, path) {
+            return true;
+        }
+        self.search_dirs
+            .iter()
+            .filter_map(|dir| path.strip_prefix(dir).ok())
+            .any(|include_name| matches(&self.include_names, include_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // folder with `include/foo/x.h`, `include/foobar/x.h` and `include/fmt/core.h`
+    fn tree() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let include = dir.path().canonicalize().unwrap().join("include");
+        for file in ["foo/x.h", "foobar/x.h", "fmt/core.h"] {
+            let path = include.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        (dir, include)
+    }
+
+    fn filter(patterns: &[&str], include: &Path) -> PathFilter {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        let search_paths = SearchPaths::from_options(&[format!("-I{}", include.display())]);
+        PathFilter::new(&patterns, &search_paths).unwrap()
+    }
+
+    #[test]
+    fn folders_match_whole_components() {
+        let (_dir, include) = tree();
+        let foo = include.join("foo").display().to_string();
+        let filter = filter(&[&foo], &include);
+        assert!(filter.contains(&include.join("foo/x.h")));
+        assert!(!filter.contains(&include.join("foobar/x.h")));
+
+        let filter = self::filter(&["foo"], &include); // include name of the folder
+        assert!(filter.contains(&include.join("foo/x.h")));
+        assert!(!filter.contains(&include.join("foobar/x.h")));
+    }
+
+    #[test]
+    fn absolute_and_relative_globs() {
+        let (_dir, include) = tree();
+        let absolute = format!("{}/foo*", include.display());
+        let filter = filter(&[&absolute], &include);
+        assert!(filter.contains(&include.join("foo/x.h")));
+        assert!(filter.contains(&include.join("foobar/x.h")));
+        assert!(!filter.contains(&include.join("fmt/core.h")));
+
+        // relative to the current directory
+        let current_dir = std::env::current_dir().unwrap().canonicalize().unwrap();
+        let filter = self::filter(&["src/*.rs"], &include);
+        assert!(filter.contains(&current_dir.join("src/lib.rs")));
+        assert!(!filter.contains(&current_dir.join("src/lib.h")));
+        // `*` doesn't cross folders
+        assert!(!filter.contains(&current_dir.join("src/nested/lib.rs")));
+    }
+
+    #[test]
+    fn include_names() {
+        let (_dir, include) = tree();
+        let filter = filter(&["fmt/*"], &include);
+        assert!(filter.contains(&include.join("fmt/core.h")));
+        assert!(!filter.contains(&include.join("foo/x.h")));
+
+        let filter = self::filter(&["fmt/core.h"], &include);
+        assert!(filter.contains(&include.join("fmt/core.h")));
+        assert!(!filter.contains(&include.join("fmt/format.h")));
+    }
+
+    #[test]
+    fn unknown_paths() {
+        let (_dir, include) = tree();
+        let search_paths = SearchPaths::from_options(&[format!("-I{}", include.display())]);
+        let result = PathFilter::new(&[String::from("missing/x.h")], &search_paths);
+        assert!(matches!(result, Err(Error::UnknownPath(_))));
+        let result = PathFilter::new(&[String::from("[")], &search_paths);
+        assert!(matches!(result, Err(Error::InvalidPattern { .. })));
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::args::{Dialect, Lang};
+use crate::error::{Error, Result};
+use crate::include_line::{self, FlagStatus, LineFile};
+use crate::utils;
+
+const PROBE_HEADER: &str = "single_header_probe.h";
+const PROBE_MACRO: &str = "SINGLE_HEADER_PROBE";
+
+/// how `-v` writes the search list
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchListFormat {
+    Plain,
+    Annotated, // entries may end with `(framework directory)` or `(headermap)`
+}
+
+/// preprocessor executable, run through the `launcher` words (`ccache`, `env VAR=value`, ...) if any
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub launcher: Vec<String>,
+    pub path: PathBuf,
+}
+
+impl Program {
+    /// command running the program, the launcher's arguments coming first
+    pub fn command(&self) -> Command {
+        match self.launcher.split_first() {
+            Some((launcher, args)) => {
+                let mut command = Command::new(launcher);
+                command.args(args).arg(&self.path);
+                command
+            }
+            None => Command::new(&self.path),
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+/// what a preprocessor executable supports, found by running it on a small input
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    // `-fdirectives-only` is accepted and keeps macros unexpanded
+    pub directives_only: bool,
+    // macros are expanded, even with `-fdirectives-only` if supported
+    pub expands_macros: bool,
+    // system headers are marked with linemarker flag 3
+    pub system_header_flag: bool,
+    pub search_list: SearchListFormat,
+}
+
+/// dialect `program --version` reports, `None` if it doesn't tell
+pub fn version_dialect(program: &Program) -> Option<Dialect> {
+    let mut command = program.command();
+    command.arg("--version");
+    let version = utils::stdout_command("C preprocessor", command).ok()?;
+    let first_line = version.first()?;
+    if first_line.contains("clang") {
+        Some(Dialect::Clang)
+    } else if version
+        .iter()
+        .any(|line| line.contains("Free Software Foundation"))
+    {
+        Some(Dialect::Gnu)
+    } else {
+        None
+    }
+}
+
+impl Capabilities {
+    /// preprocesses an input including a system header with and without `-fdirectives-only`
+    pub fn probe(
+        program: &Program,
+        dialect: Dialect,
+        required_args: &[&str],
+        lang: &Lang,
+        extra_args: &[String],
+    ) -> Result<Capabilities> {
+        let probe_error = |source| Error::Command {
+            description: "C preprocessor probe",
+            source,
+        };
+        let dir = tempfile::tempdir().map_err(probe_error)?;
+        let probe_header = dir.path().join(PROBE_HEADER);
+        std::fs::write(&probe_header, "int single_header_probe;\n").map_err(probe_error)?;
+        let input = format!("#include <{PROBE_HEADER}>\n#define {PROBE_MACRO} 0\n{PROBE_MACRO}\n");
+
+        let run = |directives_only: bool| {
+            let mut command = program.command();
+            command.args(required_args).args(["-x", lang.as_str()]);
+            if directives_only {
+                command.arg("-fdirectives-only");
+            }
+            command
+                .arg("-isystem")
+                .arg(dir.path())
+                .arg("-v")
+                .arg("-")
+                .args(extra_args);
+            utils::output_command_with_input("C preprocessor", command, input.clone())
+        };
+
+        // unknown options are either rejected or ignored, leaving the macro expanded
+        let (directives_only, (stdout, stderr)) = match run(true) {
+            Ok((stdout, stderr)) if stdout.iter().any(|line| line == PROBE_MACRO) => {
+                (true, (stdout, stderr))
+            }
+            _ => (false, run(false)?),
+        };
+
+        let expands_macros = !stdout.iter().any(|line| line == PROBE_MACRO);
+
+        let unsupported = |reason: &'static str| Error::UnsupportedPreprocessor {
+            name: program.to_string(),
+            reason,
+        };
+
+        let markers: Vec<_> = stdout
+            .iter()
+            .filter_map(|line| include_line::try_parse(line))
+            .collect();
+        if markers.is_empty() {
+            return Err(unsupported("it doesn't write linemarkers"));
+        }
+
+        let probe_header = probe_header.canonicalize().map_err(probe_error)?;
+        let opened = markers.iter().position(|marker| {
+            matches!(marker.state.status, FlagStatus::Open)
+                && matches!(&marker.file, LineFile::Path(path) if *path == probe_header)
+        });
+        let opened = opened.ok_or_else(|| unsupported("it doesn't mark included files"))?;
+        let closed = markers[opened..]
+            .iter()
+            .position(|marker| matches!(marker.state.status, FlagStatus::Close))
+            .map_or(markers.len(), |closed| opened + closed + 1);
+
+        let system_header_flag = markers[opened..closed]
+            .iter()
+            .any(|marker| marker.state.system_header);
+
+        let annotated = stderr
+            .iter()
+            .any(|line| line.ends_with("(framework directory)") || line.ends_with("(headermap)"));
+        let search_list = match dialect == Dialect::Clang || annotated {
+            true => SearchListFormat::Annotated,
+            false => SearchListFormat::Plain,
+        };
+
+        Ok(Capabilities {
+            directives_only,
+            expands_macros,
+            system_header_flag,
+            search_list,
+        })
+    }
+}
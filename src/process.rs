@@ -1,126 +1,571 @@
-use anyhow::Result;
-use std::collections::VecDeque;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use crate::directive::{self, Conditionals};
+use crate::error::{Error, Result};
+use crate::expand;
 use crate::include_line::{self, IncludeDirective};
-use crate::inline_paths::InlinePaths;
 use crate::line_zero::{LineZeroState, Skip};
-use crate::system_paths::SearchPaths;
+use crate::path_filter::PathFilter;
+use crate::source_map::Location;
+use crate::system_paths::{IncludeName, SearchPaths};
+use crate::utils;
 
 use include_line::FlagStatus;
 
+// wraps content that is implicitly `extern "C"` (linemarker flag 4) for C++ consumers
+pub const EXTERN_C_BEGIN: &[&str] = &["#ifdef __cplusplus", "extern \"C\" {", "#endif"];
+pub const EXTERN_C_END: &[&str] = &["#ifdef __cplusplus", "}", "#endif"];
+
+#[derive(Default)]
+pub struct ProcessOptions {
+    // only keep the first `#include <...>` of each system header
+    // outside of (preserved) conditionals
+    pub dedupe_system_includes: bool,
+    // track the locations of the lines with paths relative to this folder
+    pub line_directives_root: Option<PathBuf>,
+    // local headers replaced by an `#include "..."` relative to `exclude_base`
+    pub exclude_paths: PathFilter,
+    pub exclude_base: PathBuf,
+    // the preprocessor marks system headers with flag 3, else they are found by location
+    pub system_header_flag: bool,
+}
+
+/// output of `process_lines`
+pub struct Processed {
+    pub lines: Vec<String>,
+    // location of each line, if tracked
+    pub locations: Vec<Location>,
+    // include guards of the local files whose content is kept
+    pub include_guards: HashSet<String>,
+    pub warnings: Vec<String>,
+}
+
 pub fn process_lines<I: IntoIterator<Item = String>>(
     lines: I,
     search_paths: SearchPaths,
-    inline_paths: InlinePaths,
-) -> Vec<String> {
-    let mut output = vec![];
-    let mut p = Processor::new(search_paths, inline_paths);
+    inline_paths: PathFilter,
+    options: ProcessOptions,
+) -> Result<Processed> {
+    let mut p = Processor::new(search_paths, inline_paths, options);
     for line in lines {
-        if let Some(output_line) = p.process_line(line.as_str()) {
-            output.push(output_line);
+        if let Some(output_line) = p.process_line(line.as_str())? {
+            p.emit(output_line);
         }
     }
-    output
+    p.wrap_extern_c(false);
+    Ok(Processed {
+        lines: p.output,
+        locations: p.locations,
+        include_guards: p.include_guards,
+        warnings: p.warnings,
+    })
 }
 
 struct ShowContent(bool);
 
 struct Processor {
     search_paths: SearchPaths,
-    inline_paths: InlinePaths,
+    inline_paths: PathFilter,
     include_queue: VecDeque<ShowContent>,
     line_zero: LineZeroState,
+    options: ProcessOptions,
+    emitted_system_includes: HashSet<String>,
+    // preserved conditionals the next line is in
+    conditionals: Conditionals,
+    // line number and path, relative to `line_directives_root`, of the next emitted line
+    next_location: Location,
+    // the current file is implicitly `extern "C"`
+    in_extern_c: bool,
+    // the output is inside an `EXTERN_C_BEGIN` block
+    emitting_extern_c: bool,
+    // file and line number of the next line
+    location: Option<(PathBuf, usize)>,
+    // system headers already reported as not resolved by any include name
+    ambiguous_includes: HashSet<PathBuf>,
+    // lines of the files including system headers or kept, `None` if unreadable
+    sources: HashMap<PathBuf, Option<Vec<String>>>,
+    include_guards: HashSet<String>,
+    warnings: Vec<String>,
+    output: Vec<String>,
+    locations: Vec<Location>,
 }
 
 impl Processor {
-    fn new(search_paths: SearchPaths, inline_paths: InlinePaths) -> Self {
+    fn new(search_paths: SearchPaths, inline_paths: PathFilter, options: ProcessOptions) -> Self {
         Processor {
             search_paths,
             inline_paths,
             include_queue: VecDeque::new(),
             line_zero: LineZeroState::new(),
+            options,
+            emitted_system_includes: HashSet::new(),
+            conditionals: Conditionals::default(),
+            next_location: None,
+            in_extern_c: false,
+            emitting_extern_c: false,
+            location: None,
+            ambiguous_includes: HashSet::new(),
+            sources: HashMap::new(),
+            include_guards: HashSet::new(),
+            warnings: Vec::new(),
+            output: Vec::new(),
+            locations: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        self.wrap_extern_c(self.in_extern_c);
+        self.locations.push(self.next_location.clone());
+        if let Some((linenum, _)) = &mut self.next_location {
+            *linenum += 1;
+        }
+        self.output.push(line);
+    }
+
+    // open or close the `extern "C"` block the next lines are emitted in
+    fn wrap_extern_c(&mut self, extern_c: bool) {
+        if self.emitting_extern_c == extern_c {
+            return;
+        }
+        let block = if extern_c {
+            EXTERN_C_BEGIN
+        } else {
+            EXTERN_C_END
+        };
+        self.output
+            .extend(block.iter().map(|line| String::from(*line)));
+        self.locations.extend(block.iter().map(|_| None));
+        self.emitting_extern_c = extern_c;
+    }
+
+    fn is_hidding_included_lines(&self) -> bool {
+        matches!(self.include_queue.back(), Some(ShowContent(false)))
+    }
+
+    // the next visible line comes from line `linenum` of `include_info`
+    fn update_location(&mut self, include_info: &IncludeDirective) {
+        let Some(root) = &self.options.line_directives_root else {
+            return;
+        };
+        // pseudo files such as `<stdin>` have no location to point to
+        let Some(path) = include_info.absolute_path() else {
+            return;
+        };
+        if self.is_hidding_included_lines() {
+            return;
         }
+        let path = utils::relative_path(path, root).display().to_string();
+        self.next_location = Some((include_info.linenum as usize, Rc::from(path)));
     }
 
-    fn process_line<'a>(&mut self, line: &'a str) -> Option<String> {
+    fn process_line(&mut self, line: &str) -> Result<Option<String>> {
         match include_line::try_parse(line) {
             None => {
+                if let Some((_, linenum)) = &mut self.location {
+                    *linenum += 1;
+                }
                 // ignore builtin defines and includes
                 if self.line_zero.ignore_line() {
-                    return None;
+                    return Ok(None);
                 }
-                if matches!(self.include_queue.back(), Some(ShowContent(false))) {
-                    return None;
+                if self.is_hidding_included_lines() {
+                    return Ok(None);
                 }
-                Some(String::from(line))
+                self.track_conditionals(line);
+                Ok(Some(String::from(line)))
             }
             Some(include_info) => {
+                // the flags describe the file the next lines come from
+                let extern_c = include_info.state.extern_c;
+                let parent = self.location.take();
+                self.location = include_info
+                    .absolute_path()
+                    .map(|path| (path.clone(), include_info.linenum as usize));
                 if let Skip(true) = self.line_zero.feed(&include_info) {
-                    return None;
+                    self.in_extern_c = extern_c;
+                    return Ok(None);
                 }
                 if include_info.state.ignorable() {
-                    return None;
+                    // lines were skipped
+                    self.in_extern_c = extern_c;
+                    self.update_location(&include_info);
+                    self.track_include_guard(&include_info);
+                    return Ok(None);
+                }
+                // an include directive belongs to the `extern "C"` region of its includer,
+                // the consumer's compiler applies the included header's own flags
+                if let Some(include) = self.try_undo_system_include(&include_info, parent)? {
+                    self.emit(include);
                 }
-                self.try_undo_system_include(include_info)
+                self.in_extern_c = extern_c;
+                self.update_location(&include_info);
+                self.track_include_guard(&include_info);
+                Ok(None)
             }
         }
     }
 
-    fn try_undo_system_include(&mut self, include_info: IncludeDirective) -> Option<String> {
-        let state = include_info.state;
+    // `parent` is the file and line `include_info` is included from, if it opens a file
+    fn try_undo_system_include(
+        &mut self,
+        include_info: &IncludeDirective,
+        parent: Option<(PathBuf, usize)>,
+    ) -> Result<Option<String>> {
+        let in_system_header = self.is_system_header(include_info);
 
-        let Some(path) = include_info.absolute_path else {
-            panic!(
-                "include file {} in cpp output doesn't exists",
-                include_info.filename
-            );
-        };
-        let system_header = state.system_header && !self.inline_paths.should_inline(&path);
-        match state.status {
+        match include_info.state.status {
             FlagStatus::Open => {
-                // replace content of system header with its include directive
-                // don't hide local headers
+                let hidden = self.is_hidding_included_lines();
+                let Some(path) = include_info.absolute_path() else {
+                    // pseudo or missing files have no include name, only keep local content
+                    let include_state = ShowContent(!hidden && !in_system_header);
+                    self.include_queue.push_back(include_state);
+                    return Ok(None);
+                };
+                let system_header = in_system_header && !self.inline_paths.contains(path);
+                let excluded = !in_system_header && self.options.exclude_paths.contains(path);
 
-                let is_hidding_included_lines =
-                    matches!(self.include_queue.back(), Some(ShowContent(false)));
+                // replace content of system and excluded headers with their include directive
+                // don't hide other local headers
 
-                let ret = if system_header && !is_hidding_included_lines {
-                    let include = self
-                        .system_include_string(&path)
-                        .expect("Failed to create system include string from absolute path");
-                    Some(include)
+                let ret = if hidden {
+                    None
+                } else if system_header {
+                    let include = self.system_include_string(path, parent)?;
+                    self.dedupe_system_include(&include).then_some(include)
+                } else if excluded {
+                    let include = self.excluded_include_string(path);
+                    self.dedupe_system_include(&include).then_some(include)
                 } else {
                     None
                 };
 
-                let include_state = ShowContent(!system_header);
+                // headers included by hidden ones are hidden too
+                let include_state = ShowContent(!hidden && !system_header && !excluded);
                 self.include_queue.push_back(include_state);
 
-                return ret;
+                return Ok(ret);
             }
-            FlagStatus::Close => {
-                if !self.include_queue.is_empty() {
-                    self.include_queue.pop_back();
-                }
+            FlagStatus::Close if !self.include_queue.is_empty() => {
+                self.include_queue.pop_back();
             }
             _ => {}
         };
-        None
+        Ok(None)
     }
 
-    fn system_include_string(&self, filename: &PathBuf) -> Result<String> {
-        let include_name = self.search_paths.cleanup_path(filename);
+    // flag 3, or the location of the header if the preprocessor doesn't write it
+    fn is_system_header(&self, include_info: &IncludeDirective) -> bool {
+        match (
+            self.options.system_header_flag,
+            include_info.absolute_path(),
+        ) {
+            (true, _) => include_info.state.system_header,
+            (false, Some(path)) => self.search_paths.is_system_header(path),
+            (false, None) => false,
+        }
+    }
 
-        match include_name {
-            Err(err) => {
-                let ctx = format!("Failed to cleanup path: {}", filename.display());
-                Err(err.context(ctx))
-            }
-            Ok(include_name) => {
-                let include = format!("#include <{include_name}>");
-                Ok(include)
-            }
+    fn track_conditionals(&mut self, line: &str) {
+        let Some(conditional) = directive::conditional(line) else {
+            return;
+        };
+        let guards = &self.include_guards;
+        self.conditionals.feed(conditional, line, |name| {
+            guards.contains(name) || expand::is_once_guard(name)
+        });
+    }
+
+    // whether `include` should be emitted
+    fn dedupe_system_include(&mut self, include: &str) -> bool {
+        if !self.options.dedupe_system_includes {
+            return true;
+        }
+        // an include inside a conditional may not be seen by the compiler, keep the next ones
+        if self.conditionals.is_conditional() {
+            return !self.emitted_system_includes.contains(include);
         }
+        self.emitted_system_includes.insert(String::from(include))
+    }
+
+    fn source_lines(&mut self, file: &Path) -> Option<&Vec<String>> {
+        self.sources
+            .entry(file.to_path_buf())
+            .or_insert_with(|| {
+                let content = fs::read_to_string(file).ok()?;
+                Some(content.lines().map(String::from).collect())
+            })
+            .as_ref()
+    }
+
+    // the next lines come from `include_info`, remember its include guard if they are kept
+    fn track_include_guard(&mut self, include_info: &IncludeDirective) {
+        if self.is_hidding_included_lines() {
+            return;
+        }
+        let Some(path) = include_info.absolute_path() else {
+            return;
+        };
+        if self.sources.contains_key(path) {
+            return; // already seen
+        }
+        let guard = self
+            .source_lines(path)
+            .and_then(|lines| directive::include_guard(lines));
+        self.include_guards.extend(guard);
+    }
+
+    // the `#include` or `#include_next` name as written on line `linenum` of `file`,
+    // if it is the one including `path` and doesn't depend on the location of `file`
+    fn original_include(
+        &mut self,
+        (file, linenum): (PathBuf, usize),
+        path: &Path,
+    ) -> Option<IncludeName> {
+        let lines = self.source_lines(&file)?;
+        let include = directive::include(lines.get(linenum.checked_sub(1)?)?)?;
+        let (name, quoted) = (include.name, include.quoted);
+        if Path::new(name).file_name() != path.file_name() {
+            return None; // computed include or lost track of the lines
+        }
+        if quoted && file.parent()?.join(name).exists() {
+            return None; // relative to `file`
+        }
+        Some(IncludeName {
+            name: String::from(name),
+            quoted,
+            import: include.import,
+        })
+    }
+
+    fn excluded_include_string(&self, filename: &Path) -> String {
+        let path = utils::relative_path(filename, &self.options.exclude_base);
+        format!("#include \"{}\"", path.display())
+    }
+
+    // the first of the written, deepest search path and other names of `filename`
+    // the consumer's compiler resolves to `filename`, `#include_next` may have reached a shadowed one
+    fn system_include_string(
+        &mut self,
+        filename: &PathBuf,
+        parent: Option<(PathBuf, usize)>,
+    ) -> Result<String> {
+        let location = match &parent {
+            Some((file, linenum)) => format!("{}:{linenum}", file.display()),
+            None => String::from("<preprocessor output>"),
+        };
+        let original = parent.and_then(|parent| self.original_include(parent, filename));
+        // an `#import` stays one whatever the name
+        let import = original.as_ref().is_some_and(|include| include.import);
+        let deepest = self.search_paths.include_directive(filename).ok();
+        // an include spelled with the sysroot wouldn't be portable
+        let sysroot = self.search_paths.sysroot();
+        let candidates: Vec<IncludeName> = original
+            .into_iter()
+            .chain(deepest)
+            .chain(self.search_paths.include_names(filename))
+            .filter(|include| {
+                !sysroot.is_some_and(|root| Path::new(&include.name).starts_with(root))
+            })
+            .map(|include| IncludeName { import, ..include })
+            .collect();
+
+        let resolves =
+            |include: &&IncludeName| self.search_paths.resolve(include).as_ref() == Some(filename);
+        if let Some(include) = candidates.iter().find(resolves) {
+            return Ok(include.to_string());
+        }
+        let Some(include) = candidates.first() else {
+            return Err(Error::UnresolvedSystemHeader {
+                header: filename.clone(),
+                location,
+            });
+        };
+
+        if self.ambiguous_includes.insert(filename.clone()) {
+            let resolved = match self.search_paths.resolve(include) {
+                Some(path) => format!("resolves to {}", path.display()),
+                None => String::from("isn't found"),
+            };
+            self.warnings.push(format!(
+                "no include name resolves to {}, `{include}` {resolved} (shadowed by an `#include_next` wrapper?)",
+                filename.display()
+            ));
+        }
+        Ok(include.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // processed `output` with the search paths of `opts`, `{dir}` being a folder holding `files`
+    fn process(
+        output: &str,
+        files: &[(&str, &str)],
+        opts: &[&str],
+        options: ProcessOptions,
+    ) -> Processed {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().canonicalize().unwrap().display().to_string();
+        for (file, content) in files {
+            let path = Path::new(&dir).join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let opts: Vec<String> = opts.iter().map(|opt| opt.replace("{dir}", &dir)).collect();
+        let search_paths = SearchPaths::from_options(&opts);
+        let lines = output.lines().map(|line| line.replace("{dir}", &dir));
+        process_lines(lines, search_paths, PathFilter::default(), options).unwrap()
+    }
+
+    fn flagged() -> ProcessOptions {
+        ProcessOptions {
+            system_header_flag: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn include_guards_dont_prevent_dedupe() {
+        let guarded =
+            |name: &str| format!("#ifndef {name}\n#define {name}\n#include <vector>\n#endif");
+        let files = [
+            ("main.h", String::from("#include \"a.h\"\n#include \"b.h\"")),
+            ("a.h", guarded("A_H")),
+            ("b.h", guarded("B_H")),
+            ("sys/vector", String::new()),
+        ];
+        let files: Vec<_> = files
+            .iter()
+            .map(|(file, content)| (*file, content.as_str()))
+            .collect();
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "{dir}/a.h" 1
+#ifndef A_H
+#define A_H
+# 1 "{dir}/sys/vector" 1 3
+# 4 "{dir}/a.h" 2
+#endif
+# 2 "{dir}/main.h" 2
+# 1 "{dir}/b.h" 1
+#ifndef B_H
+#define B_H
+# 1 "{dir}/sys/vector" 1 3
+# 4 "{dir}/b.h" 2
+#endif
+# 3 "{dir}/main.h" 2"#;
+        let options = ProcessOptions {
+            dedupe_system_includes: true,
+            ..flagged()
+        };
+        let processed = process(output, &files, &["-isystem{dir}/sys"], options);
+        let includes = processed
+            .lines
+            .iter()
+            .filter(|line| *line == "#include <vector>");
+        assert_eq!(includes.count(), 1);
+        assert!(processed.include_guards.contains("B_H"));
+    }
+
+    #[test]
+    fn wraps_implicitly_extern_c_content() {
+        let files = [
+            ("main.h", "#include \"c.h\"\nint y;"),
+            ("c.h", "int x;\n#include <b.h>"),
+            ("sys/b.h", ""),
+        ];
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "{dir}/c.h" 1 4
+int x;
+# 1 "{dir}/sys/b.h" 1 3 4
+# 3 "{dir}/c.h" 2 4
+# 2 "{dir}/main.h" 2
+int y;"#;
+        let processed = process(output, &files, &["-isystem{dir}/sys"], flagged());
+        let mut expected = vec!["int x;", "#include <b.h>"];
+        expected.splice(0..0, EXTERN_C_BEGIN.iter().copied());
+        expected.extend(EXTERN_C_END);
+        expected.push("int y;");
+        assert_eq!(processed.lines, expected);
+    }
+
+    #[test]
+    fn keeps_the_written_include_name() {
+        // `<x.h>` through the deepest search path, but written relative to `sys`
+        let files = [
+            (
+                "main.h",
+                "#include <inner/x.h>\n#include <inner/x.h> // again",
+            ),
+            ("sys/inner/x.h", ""),
+        ];
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "{dir}/sys/inner/x.h" 1 3
+# 2 "{dir}/main.h" 2
+# 1 "{dir}/sys/inner/x.h" 1 3
+# 3 "{dir}/main.h" 2"#;
+        let opts = ["-isystem{dir}/sys", "-isystem{dir}/sys/inner"];
+        let processed = process(output, &files, &opts, flagged());
+        assert_eq!(
+            processed.lines,
+            ["#include <inner/x.h>", "#include <inner/x.h>"]
+        );
+
+        // a name relative to the including file isn't portable
+        let files = [
+            ("main.h", "#include \"sys/inner/x.h\""),
+            ("sys/inner/x.h", ""),
+        ];
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "{dir}/sys/inner/x.h" 1 3
+# 2 "{dir}/main.h" 2"#;
+        let processed = process(output, &files, &opts, flagged());
+        assert_eq!(processed.lines, ["#include <x.h>"]);
+    }
+
+    #[test]
+    fn resolves_the_header_include_next_reached() {
+        // `wrap.h` reaches the `x.h` shadowed by `sys/x.h`
+        let files = [
+            ("main.h", "#include \"wrap.h\""),
+            ("wrap.h", "#include_next <x.h>"),
+            ("sys/x.h", ""),
+            ("sys/sub/x.h", ""),
+        ];
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "{dir}/wrap.h" 1
+# 1 "{dir}/sys/sub/x.h" 1 3
+# 2 "{dir}/wrap.h" 2
+# 2 "{dir}/main.h" 2"#;
+        let opts = ["-isystem{dir}/sys", "-isystem{dir}/sys/sub"];
+        let processed = process(output, &files, &opts, flagged());
+        assert_eq!(processed.lines, ["#include <sub/x.h>"]);
+        assert!(processed.warnings.is_empty());
+
+        // no name reaches it, the written one is kept with a single warning
+        let files = [
+            ("main.h", "#include \"wrap.h\"\n#include \"wrap.h\""),
+            ("wrap.h", "#include_next <x.h>"),
+            ("sys/x.h", ""),
+            ("sys2/x.h", ""),
+        ];
+        let output = r#"# 1 "{dir}/main.h"
+# 1 "{dir}/wrap.h" 1
+# 1 "{dir}/sys2/x.h" 1 3
+# 2 "{dir}/wrap.h" 2
+# 2 "{dir}/main.h" 2
+# 1 "{dir}/wrap.h" 1
+# 1 "{dir}/sys2/x.h" 1 3
+# 2 "{dir}/wrap.h" 2
+# 3 "{dir}/main.h" 2"#;
+        let opts = ["-isystem{dir}/sys", "-isystem{dir}/sys2"];
+        let processed = process(output, &files, &opts, flagged());
+        assert_eq!(processed.lines, ["#include <x.h>", "#include <x.h>"]);
+        assert_eq!(processed.warnings.len(), 1);
     }
 }
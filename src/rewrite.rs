@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::path::PathBuf;
+
+use crate::directive;
+use crate::expand::IncludeDirs;
+use crate::include_line::{self, FlagStatus, IncludeDirective};
+
+// Undoes the rewrites of `clang -E -frewrite-includes` that `process_lines` can't read:
+// - every `#include` is commented out in an `#if 0 /* expanded by -frewrite-includes */` block,
+//   followed by the linemarkers and content of the file if it was entered
+// - `#if` / `#elif` conditions are disabled and replaced by the value clang evaluated
+
+const EXPANDED_BEGIN: &str = "#if 0 /* expanded by -frewrite-includes */";
+const EXPANDED_END: &str = "#endif /* expanded by -frewrite-includes */";
+const DISABLED_BEGIN: &str = "#if 0 /* disabled by -frewrite-includes */";
+const DISABLED_END: &str = "#endif /* disabled by -frewrite-includes */";
+const EVALUATED: &str = "/* evaluated by -frewrite-includes */";
+
+// lines up to `end`, which is consumed but not returned
+fn take_until<I: Iterator<Item = String>>(lines: &mut I, end: &str) -> Vec<String> {
+    lines.take_while(|line| line != end).collect()
+}
+
+fn is_open_marker(line: &str) -> bool {
+    include_line::try_parse(line)
+        .is_some_and(|marker| matches!(marker.state.status, FlagStatus::Open))
+}
+
+fn is_line_info(line: &str) -> bool {
+    include_line::try_parse(line).is_some_and(|marker| marker.state.ignorable())
+}
+
+struct Restorer<'a> {
+    dirs: &'a IncludeDirs,
+    // files clang entered so far
+    entered: HashSet<PathBuf>,
+    output: Vec<String>,
+    warnings: &'a mut Vec<String>,
+}
+
+impl Restorer<'_> {
+    // `#if 0` `#if <condition>` `#endif` `#endif` `#if 1`, or with `#elif` when it is one
+    fn restore_condition<I: Iterator<Item = String>>(&mut self, lines: &mut Peekable<I>) {
+        let mut condition = take_until(lines, DISABLED_END);
+        condition.pop(); // `#endif` closing the condition's empty block
+        let evaluated = lines.next_if(|line| line.contains(EVALUATED));
+        let elif = evaluated.is_some_and(|line| line.trim_start().starts_with("#elif"));
+        if elif && !condition.is_empty() {
+            condition.remove(0); // `#if 0` opening the `#elif`
+        }
+        self.output.extend(condition);
+    }
+
+    // drops the commented out `#include` of entered files and of files already entered,
+    // the others are in branches clang didn't take and are restored
+    fn restore_include<I: Iterator<Item = String>>(&mut self, lines: &mut Peekable<I>) {
+        let directive = take_until(lines, EXPANDED_END);
+        let mut line_infos = Vec::new();
+        while let Some(line) = lines.next_if(|line| is_line_info(line)) {
+            line_infos.push(line);
+        }
+        let entered = lines.peek().is_some_and(|line| is_open_marker(line));
+        let includer = line_infos
+            .first()
+            .and_then(|line| include_line::try_parse(line));
+
+        match includer {
+            _ if entered => {}
+            Some(includer) => self.restore_skipped_include(directive, &includer),
+            None => self.output.extend(directive),
+        }
+        self.output.extend(line_infos);
+    }
+
+    fn restore_skipped_include(&mut self, directive: Vec<String>, includer: &IncludeDirective) {
+        let code: String = directive
+            .iter()
+            .map(|line| line.trim_end_matches('\\'))
+            .collect();
+        let resolved = includer.absolute_path().and_then(|path| {
+            let include = directive::include(&code)?;
+            let resolved = self
+                .dirs
+                .resolve(&include, path, includer.state.system_header)?;
+            Some((path, resolved))
+        });
+        match resolved {
+            // skipped because of an include guard or `#pragma once`
+            Some((_, resolved)) if self.entered.contains(&resolved.path) => return,
+            Some((path, resolved)) if !resolved.system => self.warnings.push(format!(
+                "{}:{}: `{}` is in a branch clang didn't take, it is kept as written",
+                path.display(),
+                includer.linenum,
+                code.trim()
+            )),
+            _ => {}
+        }
+        self.output.extend(directive);
+    }
+}
+
+/// `clang -frewrite-includes` output as the linemarker stream `process_lines` consumes,
+/// with the conditionals and not expanded `#include`s as written
+pub fn restore_rewritten(
+    lines: Vec<String>,
+    dirs: &IncludeDirs,
+    warnings: &mut Vec<String>,
+) -> Vec<String> {
+    let mut restorer = Restorer {
+        dirs,
+        entered: HashSet::new(),
+        output: Vec::new(),
+        warnings,
+    };
+    let mut lines = lines.into_iter().peekable();
+    while let Some(line) = lines.next() {
+        match line.as_str() {
+            DISABLED_BEGIN => restorer.restore_condition(&mut lines),
+            EXPANDED_BEGIN => restorer.restore_include(&mut lines),
+            _ => {
+                let opened = include_line::try_parse(&line)
+                    .filter(|marker| matches!(marker.state.status, FlagStatus::Open));
+                if let Some(path) = opened.as_ref().and_then(|marker| marker.absolute_path()) {
+                    restorer.entered.insert(path.clone());
+                }
+                restorer.output.push(line);
+            }
+        }
+    }
+    restorer.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system_paths::SearchPaths;
+
+    // `clang -E -frewrite-includes main.h` output, reduced to the interesting lines,
+    // `{dir}` being the folder of `main.h`:
+    // ```c
+    // #include "sub.h"
+    // #include "sub.h"
+    // #if __has_include(<stdio.h>)
+    // int has_stdio;
+    // #elif __has_include("other.h")
+    // #include "other.h"
+    // #endif
+    // #include "guarded.h"
+    // int main_h;
+    // ```
+    // `sub.h` has a `#pragma once`, `guarded.h` is guarded by a macro defined on the command line
+    const REWRITTEN: &str = r#"# 1 "{dir}/main.h"
+# 1 "<built-in>" 1
+# 1 "<built-in>" 3
+# 380 "<built-in>" 3
+# 1 "<command line>" 1
+#define GUARDED_H 1
+# 1 "<built-in>" 2
+# 1 "{dir}/main.h" 2
+#if 0 /* expanded by -frewrite-includes */
+#include "sub.h"
+#endif /* expanded by -frewrite-includes */
+# 1 "{dir}/main.h"
+# 1 "{dir}/sub.h" 1
+#pragma once
+int sub;
+# 2 "{dir}/main.h" 2
+#if 0 /* expanded by -frewrite-includes */
+#include "sub.h"
+#endif /* expanded by -frewrite-includes */
+# 2 "{dir}/main.h"
+# 3 "{dir}/main.h"
+#if 0 /* disabled by -frewrite-includes */
+#if __has_include(<stdio.h>)
+#endif
+#endif /* disabled by -frewrite-includes */
+#if 1 /* evaluated by -frewrite-includes */
+# 4 "{dir}/main.h"
+int has_stdio;
+#if 0 /* disabled by -frewrite-includes */
+#if 0
+#elif __has_include("other.h")
+#endif
+#endif /* disabled by -frewrite-includes */
+#elif 0 /* evaluated by -frewrite-includes */
+# 6 "{dir}/main.h"
+#include "other.h"
+#endif
+#if 0 /* expanded by -frewrite-includes */
+#include "guarded.h"
+#endif /* expanded by -frewrite-includes */
+# 8 "{dir}/main.h"
+# 9 "{dir}/main.h"
+int main_h;"#;
+
+    const RESTORED: &str = r#"# 1 "{dir}/main.h"
+# 1 "<built-in>" 1
+# 1 "<built-in>" 3
+# 380 "<built-in>" 3
+# 1 "<command line>" 1
+#define GUARDED_H 1
+# 1 "<built-in>" 2
+# 1 "{dir}/main.h" 2
+# 1 "{dir}/main.h"
+# 1 "{dir}/sub.h" 1
+#pragma once
+int sub;
+# 2 "{dir}/main.h" 2
+# 2 "{dir}/main.h"
+# 3 "{dir}/main.h"
+#if __has_include(<stdio.h>)
+# 4 "{dir}/main.h"
+int has_stdio;
+#elif __has_include("other.h")
+# 6 "{dir}/main.h"
+#include "other.h"
+#endif
+#include "guarded.h"
+# 8 "{dir}/main.h"
+# 9 "{dir}/main.h"
+int main_h;"#;
+
+    #[test]
+    fn restores_includes_and_conditions() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["main.h", "sub.h", "guarded.h"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        let dir = dir.path().canonicalize().unwrap().display().to_string();
+        let lines = |text: &str| -> Vec<String> {
+            text.replace("{dir}", &dir)
+                .lines()
+                .map(String::from)
+                .collect()
+        };
+
+        let dirs = IncludeDirs::new(&SearchPaths::from_options(&[]));
+        let mut warnings = Vec::new();
+        let restored = restore_rewritten(lines(REWRITTEN), &dirs, &mut warnings);
+        assert_eq!(restored, lines(RESTORED));
+        assert_eq!(
+            warnings,
+            [format!(
+                "{dir}/main.h:8: `#include \"guarded.h\"` is in a branch clang didn't take, it is kept as written"
+            )]
+        );
+    }
+}
@@ -0,0 +1,767 @@
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::args::{Dialect, Lang, Preprocessor, Protection};
+use crate::error::{Error, Result};
+use crate::expr::KnownMacros;
+use crate::path_filter::PathFilter;
+use crate::probe::{self, Capabilities, Program};
+use crate::process::{process_lines, ProcessOptions};
+use crate::source_map::{Location, SourceMap};
+use crate::system_paths::SearchPaths;
+use crate::{amalgamate, args, cmake, expand, hoist, rewrite, unifdef, utils};
+
+// lines of the output and their locations
+type Lines = (Vec<String>, Vec<Location>);
+
+// external preprocessor and how to run it
+struct Toolchain {
+    preprocessor: Program,
+    capabilities: Capabilities,
+    base_args: Vec<&'static str>,
+    extra_cpp_opts: Vec<String>,
+    // output of `-frewrite-includes` to restore
+    rewrite: bool,
+}
+
+// receives the warnings of a generation
+#[derive(Clone)]
+struct WarningHandler(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for WarningHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningHandler")
+    }
+}
+
+/// Builder describing how to generate a single header from a C/C++ file
+#[derive(Clone, Debug)]
+pub struct SingleHeader {
+    inputs: Vec<PathBuf>,
+    preprocessor: Option<Preprocessor>,
+    preprocessor_path: Option<PathBuf>,
+    launcher: Vec<String>,
+    dialect: Option<Dialect>,
+    target: Option<String>,
+    sysroot: Option<PathBuf>,
+    lang: Lang,
+    protection: Protection,
+    cmake: Option<PathBuf>,
+    inline_paths: Vec<String>,
+    exclude_paths: Vec<String>,
+    exclude_base: Option<PathBuf>,
+    cpp_opts: Vec<String>,
+    implementation: Vec<PathBuf>,
+    preserve_conditionals: bool,
+    known_macros: KnownMacros,
+    line_directives: bool,
+    line_root: Option<PathBuf>,
+    source_map: bool,
+    dedupe_system_includes: bool,
+    hoist_system_includes: bool,
+    on_warning: Option<WarningHandler>,
+}
+
+impl SingleHeader {
+    pub fn new(file: impl Into<PathBuf>) -> Self {
+        SingleHeader {
+            inputs: vec![file.into()],
+            preprocessor: None,
+            preprocessor_path: None,
+            launcher: Vec::new(),
+            dialect: None,
+            target: None,
+            sysroot: None,
+            lang: Lang::Cpp,
+            protection: Protection::Ifndef,
+            cmake: None,
+            inline_paths: Vec::new(),
+            exclude_paths: Vec::new(),
+            exclude_base: None,
+            cpp_opts: Vec::new(),
+            implementation: Vec::new(),
+            preserve_conditionals: false,
+            known_macros: KnownMacros::new(),
+            line_directives: false,
+            line_root: None,
+            source_map: false,
+            dedupe_system_includes: false,
+            hoist_system_includes: false,
+            on_warning: None,
+        }
+    }
+
+    /// additional header, or folder whose top-level headers are amalgamated into the single header
+    pub fn input(mut self, path: impl Into<PathBuf>) -> Self {
+        self.inputs.push(path.into());
+        self
+    }
+
+    pub fn inputs<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.inputs.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// first of `cpp`, `gcc` and `clang` found in the `PATH` by default
+    pub fn preprocessor(mut self, preprocessor: Preprocessor) -> Self {
+        self.preprocessor = Some(preprocessor);
+        self
+    }
+
+    /// run any preprocessor executable (`gcc-13`, `arm-none-eabi-gcc`, ...) instead of `preprocessor`
+    pub fn preprocessor_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.preprocessor_path = Some(path.into());
+        self
+    }
+
+    /// command the preprocessor is run through, such as `ccache` or `env VAR=value`
+    pub fn launcher<I, S>(mut self, words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.launcher.extend(words.into_iter().map(Into::into));
+        self
+    }
+
+    /// how to drive the preprocessor, found from its `--version` or its name by default
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// target triple to generate the single header for,
+    /// selects the `<target>-<preprocessor>` executable of GNU toolchains,
+    /// a GNU `preprocessor_path` must be that executable
+    pub fn target(mut self, triple: impl Into<String>) -> Self {
+        self.target = Some(triple.into());
+        self
+    }
+
+    /// root of the target's headers and libraries
+    pub fn sysroot(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.sysroot = Some(folder.into());
+        self
+    }
+
+    pub fn lang(mut self, lang: Lang) -> Self {
+        self.lang = lang;
+        self
+    }
+
+    pub fn protection(mut self, protection: Protection) -> Self {
+        self.protection = protection;
+        self
+    }
+
+    /// build folder containing the `compile_commands.json` describing how to compile the file
+    pub fn cmake(mut self, build_dir: impl Into<PathBuf>) -> Self {
+        self.cmake = Some(build_dir.into());
+        self
+    }
+
+    /// path, glob or include name of headers that must allways be `#include` expanded
+    pub fn inline_path(mut self, path: impl Into<String>) -> Self {
+        self.inline_paths.push(path.into());
+        self
+    }
+
+    pub fn inline_paths<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.inline_paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// path, glob or include name of local headers to keep as an `#include "..."` instead of inlining them
+    pub fn exclude_path(mut self, path: impl Into<String>) -> Self {
+        self.exclude_paths.push(path.into());
+        self
+    }
+
+    pub fn exclude_paths<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.exclude_paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// folder the `#include` of excluded headers are relative to,
+    /// defaults to the folder of the first input
+    pub fn exclude_base(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.exclude_base = Some(folder.into());
+        self
+    }
+
+    /// additional parameter for the preprocessor
+    pub fn cpp_opt(mut self, opt: impl Into<String>) -> Self {
+        self.cpp_opts.push(opt.into());
+        self
+    }
+
+    pub fn cpp_opts<I, S>(mut self, opts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.cpp_opts.extend(opts.into_iter().map(Into::into));
+        self
+    }
+
+    /// C/C++ source whose content goes in the `#ifdef <NAME>_IMPLEMENTATION` section (STB-style)
+    pub fn implementation_source(mut self, path: impl Into<PathBuf>) -> Self {
+        self.implementation.push(path.into());
+        self
+    }
+
+    pub fn implementation_sources<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.implementation
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// keep the `#if/#ifdef/#elif/#else/#endif` of local headers as written instead of evaluating them
+    ///
+    /// local `#include`s are expanded by a built-in directive-aware expander instead of the preprocessor
+    pub fn preserve_conditionals(mut self, preserve: bool) -> Self {
+        self.preserve_conditionals = preserve;
+        self
+    }
+
+    /// evaluate the conditionals depending on `name` as if it was defined to `value`
+    ///
+    /// conditionals only depending on other macros are kept as written, mixed ones are simplified,
+    /// implies [`preserve_conditionals`](Self::preserve_conditionals)
+    pub fn eval_defined(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.known_macros.insert(name.into(), Some(value.into()));
+        self
+    }
+
+    /// evaluate the conditionals depending on `name` as if it was undefined,
+    /// implies [`preserve_conditionals`](Self::preserve_conditionals)
+    pub fn eval_undefined(mut self, name: impl Into<String>) -> Self {
+        self.known_macros.insert(name.into(), None);
+        self
+    }
+
+    /// only keep the first `#include <...>` of each system header
+    ///
+    /// always enabled when amalgamating several files or with implementation sources
+    pub fn dedupe_system_includes(mut self, enable: bool) -> Self {
+        self.dedupe_system_includes = enable;
+        self
+    }
+
+    /// move the system includes to a sorted block at the top of the single header
+    ///
+    /// includes inside conditionals or following a `#define` or `#undef` other than an include guard,
+    /// or an `#include "..."`, that may change what system headers declare, stay in place
+    pub fn hoist_system_includes(mut self, enable: bool) -> Self {
+        self.hoist_system_includes = enable;
+        self
+    }
+
+    /// emit `#line` directives so diagnostics point back to the original files
+    pub fn line_directives(mut self, enable: bool) -> Self {
+        self.line_directives = enable;
+        self
+    }
+
+    /// also write a source map next to the file generated by `write_to_file`
+    pub fn source_map(mut self, enable: bool) -> Self {
+        self.source_map = enable;
+        self
+    }
+
+    /// folder the `#line` directive and source map paths are relative to,
+    /// defaults to the current directory
+    pub fn line_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.line_root = Some(root.into());
+        self
+    }
+
+    /// called with each warning of the generation, they are dropped by default
+    pub fn on_warning(mut self, handler: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_warning = Some(WarningHandler(Arc::new(handler)));
+        self
+    }
+
+    /// generate the single header and return it as a string
+    pub fn generate(&self) -> Result<String> {
+        let mut output = Vec::new();
+        self.write_to(&mut output)?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// generate the single header along with its source map
+    pub fn generate_with_source_map(&self) -> Result<(String, SourceMap)> {
+        let (output, source_map) = self.render(true)?;
+        let output = String::from_utf8_lossy(&output).into_owned();
+        Ok((output, source_map))
+    }
+
+    /// generate the single header and write it to `out`
+    pub fn write_to<W: Write>(&self, mut out: W) -> Result<()> {
+        let (output, _) = self.render(false)?;
+        out.write_all(&output)?;
+        Ok(())
+    }
+
+    /// generate the single header into `path`, and its source map into `<path>.map` if enabled
+    ///
+    /// the files are only replaced once generation succeeded,
+    /// and are left untouched (mtime included) if their content didn't change
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let (output, mut source_map) = self.render(self.source_map)?;
+        utils::replace_file_if_changed(path, &output)?;
+
+        if self.source_map {
+            let file_name = path.file_name().unwrap_or_default();
+            source_map.file = file_name.to_string_lossy().into_owned();
+            let json = serde_json::to_vec_pretty(&source_map).expect("source map is serializable");
+            utils::replace_file_if_changed(&SourceMap::sidecar_path(path), &json)?;
+        }
+        Ok(())
+    }
+
+    fn render(&self, with_source_map: bool) -> Result<(Vec<u8>, SourceMap)> {
+        let (declarations, implementation) = self.process(with_source_map)?;
+        let filename = self.inputs[0].display().to_string();
+
+        let mut output = Vec::new();
+        let mut source_map = SourceMap::new();
+        let mut write_lines = |out: &mut Vec<u8>, (lines, locations): &Lines| {
+            source_map.write_lines(out, lines, locations, self.line_directives)
+        };
+
+        self.protection.protect(&mut output, &filename, |out| {
+            write_lines(out, &declarations)
+        })?;
+        if !self.implementation.is_empty() {
+            args::implementation_section(&mut output, &filename, |out| {
+                write_lines(out, &implementation)
+            })?;
+        }
+        Ok((output, source_map))
+    }
+
+    fn warn(&self, warnings: &[String]) {
+        if let Some(WarningHandler(handler)) = &self.on_warning {
+            warnings.iter().for_each(|warning| handler(warning));
+        }
+    }
+
+    // the preprocessor would evaluate the conditionals `--eval` works on
+    fn preserves_conditionals(&self) -> bool {
+        self.preserve_conditionals || !self.known_macros.is_empty()
+    }
+
+    // returns the declarations and implementation parts,
+    // with the locations of their lines if `line_directives` or `with_source_map`
+    fn process(&self, with_source_map: bool) -> Result<(Lines, Lines)> {
+        if let Some(name) = self.known_macros.keys().find(|name| !is_identifier(name)) {
+            return Err(Error::InvalidMacro(name.clone()));
+        }
+
+        let files = self.input_files()?;
+        let sources = self.implementation_files()?;
+
+        // deduped per file, an option and its value can be two arguments (`-I dir`)
+        let mut file_opts = Vec::new();
+        if let Some(cmake) = &self.cmake {
+            let compile_commands = cmake::compile_commands(cmake)?;
+            for file in files.iter().chain(&sources) {
+                let file = file.display().to_string();
+                let opts = cmake::cmake_options(&compile_commands, &file).map_err(Error::Cmake)?;
+                utils::push_unique(&mut file_opts, opts);
+            }
+        }
+        let user_opts = utils::merge(file_opts.concat(), self.cpp_opts.clone());
+
+        let builtin = self.preprocessor_path.is_none()
+            && matches!(self.preprocessor, Some(Preprocessor::Builtin));
+        let (toolchain, search_paths) = match builtin {
+            true => {
+                let ignored = macro_opts(&user_opts);
+                if !ignored.is_empty() {
+                    self.warn(&[format!(
+                        "the builtin preprocessor keeps the conditionals as written and ignores `{}`",
+                        ignored.join(" ")
+                    )]);
+                }
+                let extra_cpp_opts = utils::merge(self.toolchain_opts(Dialect::Gnu)?, user_opts);
+                (None, SearchPaths::from_options(&extra_cpp_opts))
+            }
+            false => {
+                let (toolchain, search_paths) = self.toolchain(user_opts)?;
+                (Some(toolchain), search_paths)
+            }
+        };
+
+        let inline_paths = PathFilter::new(&self.inline_paths, &search_paths)?;
+        let exclude_paths = PathFilter::new(&self.exclude_paths, &search_paths)?;
+
+        let lines = match &toolchain {
+            Some(toolchain) if !self.preserves_conditionals() => {
+                let command = toolchain.preprocessor.command();
+                let lines = self.preprocess(
+                    command,
+                    &files,
+                    &sources,
+                    &toolchain.base_args,
+                    toolchain.extra_cpp_opts.clone(),
+                )?;
+                match toolchain.rewrite {
+                    true => {
+                        let dirs = expand::IncludeDirs::new(&search_paths);
+                        let mut warnings = Vec::new();
+                        let lines = rewrite::restore_rewritten(lines, &dirs, &mut warnings);
+                        self.warn(&warnings);
+                        lines
+                    }
+                    false => lines,
+                }
+            }
+            _ => {
+                let dirs = expand::IncludeDirs::new(&search_paths);
+                self.expand(&files, &sources, &dirs, &inline_paths, &exclude_paths)?
+            }
+        };
+        // the built-in expander always marks them
+        let system_header_flag = match &toolchain {
+            Some(toolchain) if !self.preserves_conditionals() => {
+                toolchain.capabilities.system_header_flag
+            }
+            _ => true,
+        };
+
+        // system includes are shared by all inputs and by the implementation
+        let options = ProcessOptions {
+            dedupe_system_includes: self.dedupe_system_includes
+                || files.len() > 1
+                || !sources.is_empty(),
+            line_directives_root: self.line_directives_root(with_source_map)?,
+            exclude_paths,
+            exclude_base: self.absolute_exclude_base(&files)?,
+            system_header_flag,
+        };
+        let processed = process_lines(lines, search_paths, inline_paths, options)?;
+        self.warn(&processed.warnings);
+        let (mut declarations, mut locations) =
+            unifdef::unifdef(processed.lines, processed.locations, &self.known_macros);
+
+        let implementation = match declarations
+            .iter()
+            .position(|line| line == amalgamate::IMPLEMENTATION_MARKER)
+        {
+            Some(marker) => (
+                declarations.split_off(marker).split_off(1),
+                locations.split_off(marker).split_off(1),
+            ),
+            None => (Vec::new(), Vec::new()),
+        };
+        if self.hoist_system_includes {
+            let mut warnings = Vec::new();
+            let count = declarations.len();
+            declarations = hoist::hoist_system_includes(
+                declarations,
+                &processed.include_guards,
+                &mut warnings,
+            );
+            // the other lines are shifted by the hoisted block
+            let hoisted = declarations.len() - count;
+            locations.splice(0..0, vec![None; hoisted]);
+            self.warn(&warnings);
+        }
+        Ok(((declarations, locations), implementation))
+    }
+
+    fn preprocess(
+        &self,
+        mut command: Command,
+        files: &[PathBuf],
+        sources: &[PathBuf],
+        base_preprocessor_args: &[&str],
+        extra_cpp_opts: Vec<String>,
+    ) -> Result<Vec<String>> {
+        command.args(base_preprocessor_args);
+
+        match files {
+            [file] if sources.is_empty() => {
+                command.arg(file).args(extra_cpp_opts);
+                utils::stdout_command("C preprocessor", command)
+            }
+            files => {
+                // amalgamate through a source including every file, read from stdin
+                command.arg("-").args(extra_cpp_opts);
+                let umbrella = amalgamate::umbrella_source(files, sources);
+                utils::stdout_command_with_input("C preprocessor", command, umbrella)
+            }
+        }
+    }
+
+    // built-in replacement of `preprocess` that doesn't evaluate conditionals
+    fn expand(
+        &self,
+        files: &[PathBuf],
+        sources: &[PathBuf],
+        dirs: &expand::IncludeDirs,
+        inline_paths: &PathFilter,
+        exclude_paths: &PathFilter,
+    ) -> Result<Vec<String>> {
+        let (path, source) = match files {
+            [file] if sources.is_empty() => {
+                let source = fs::read_to_string(file).map_err(|source| Error::Input {
+                    path: file.clone(),
+                    source,
+                })?;
+                (file.clone(), source)
+            }
+            files => {
+                let umbrella = amalgamate::umbrella_source(files, sources);
+                (PathBuf::from("<stdin>"), umbrella)
+            }
+        };
+        expand::expand_source(&path, &source, dirs, inline_paths, exclude_paths)
+            .map_err(|source| Error::Input { path, source })
+    }
+
+    // finds and probes the preprocessor, and asks it for its search paths
+    fn toolchain(&self, user_opts: Vec<String>) -> Result<(Toolchain, SearchPaths)> {
+        let (preprocessor, guessed_dialect, required_args) = self.find_preprocessor()?;
+        let dialect = self
+            .dialect
+            .or_else(|| probe::version_dialect(&preprocessor))
+            .unwrap_or(guessed_dialect);
+        check_target(self.target.as_deref(), &preprocessor.path, dialect)?;
+        let extra_cpp_opts = utils::merge(self.toolchain_opts(dialect)?, user_opts);
+
+        let capabilities = Capabilities::probe(
+            &preprocessor,
+            dialect,
+            &required_args,
+            &self.lang,
+            &extra_cpp_opts,
+        )?;
+        // `-frewrite-includes` keeps macros unexpanded on its own
+        let rewrite = self.preprocessor_path.is_none()
+            && self
+                .preprocessor
+                .as_ref()
+                .is_some_and(Preprocessor::rewrites_includes);
+        if capabilities.expands_macros && !rewrite && !self.preserves_conditionals() {
+            self.warn(&[format!(
+                "{preprocessor} doesn't support -fdirectives-only, macros are expanded"
+            )]);
+        }
+        let directives_only = capabilities.directives_only && !rewrite;
+        let base_args = base_args(required_args, &self.lang, directives_only);
+
+        let search_paths = SearchPaths::new(
+            &preprocessor,
+            capabilities.search_list,
+            &base_args,
+            &extra_cpp_opts,
+        )?;
+        let toolchain = Toolchain {
+            preprocessor,
+            capabilities,
+            base_args,
+            extra_cpp_opts,
+            rewrite,
+        };
+        Ok((toolchain, search_paths))
+    }
+
+    // executable with the dialect guessed from its name and the arguments it requires
+    fn find_preprocessor(&self) -> Result<(Program, Dialect, Vec<&'static str>)> {
+        let not_found = |name: &Path, source| Error::PreprocessorNotFound {
+            name: name.display().to_string(),
+            source,
+        };
+        let program = |path| Program {
+            launcher: self.launcher.clone(),
+            path,
+        };
+        if let Some(path) = &self.preprocessor_path {
+            let dialect = self.dialect.unwrap_or_else(|| Dialect::guess(path));
+            let path = which::which(path).map_err(|source| not_found(path, source))?;
+            return Ok((program(path), dialect, dialect.required_args()));
+        }
+
+        let candidates = match &self.preprocessor {
+            Some(preprocessor) => vec![preprocessor.clone()],
+            None => vec![Preprocessor::Cpp, Preprocessor::Gcc, Preprocessor::Clang],
+        };
+        let mut first_error = None;
+        for preprocessor in candidates {
+            let dialect = self.dialect.unwrap_or(preprocessor.dialect());
+            let name = match (&self.target, dialect) {
+                // GNU toolchains are selected by their prefix
+                (Some(target), Dialect::Gnu) => format!("{target}-{}", preprocessor.program()),
+                _ => String::from(preprocessor.program()),
+            };
+            let name = PathBuf::from(name);
+            match which::which(&name) {
+                Ok(path) => return Ok((program(path), dialect, preprocessor.required_args())),
+                Err(source) => {
+                    first_error.get_or_insert_with(|| not_found(&name, source));
+                }
+            }
+        }
+        Err(first_error.expect("there is at least one candidate"))
+    }
+
+    // `--target` and `--sysroot` options, given to both the search path probe and the main run
+    fn toolchain_opts(&self, dialect: Dialect) -> Result<Vec<String>> {
+        let mut opts = Vec::new();
+        if let Some(target) = &self.target {
+            opts.extend(dialect.target_args(target));
+        }
+        if let Some(sysroot) = &self.sysroot {
+            let sysroot = sysroot.canonicalize().map_err(|source| Error::Input {
+                path: sysroot.clone(),
+                source,
+            })?;
+            opts.push(format!("--sysroot={}", sysroot.display()));
+        }
+        Ok(opts)
+    }
+
+    fn absolute_exclude_base(&self, files: &[PathBuf]) -> Result<PathBuf> {
+        let base = self.exclude_base.as_ref().unwrap_or(&files[0]);
+        let absolute_base = base.canonicalize().map_err(|source| Error::Input {
+            path: base.clone(),
+            source,
+        })?;
+        match &self.exclude_base {
+            Some(_) => Ok(absolute_base),
+            None => Ok(absolute_base
+                .parent()
+                .unwrap_or(&absolute_base)
+                .to_path_buf()),
+        }
+    }
+
+    fn line_directives_root(&self, with_source_map: bool) -> Result<Option<PathBuf>> {
+        if !self.line_directives && !with_source_map {
+            return Ok(None);
+        }
+        let root = self.line_root.clone().unwrap_or_else(|| PathBuf::from("."));
+        let absolute_root = root
+            .canonicalize()
+            .map_err(|source| Error::Input { path: root, source })?;
+        Ok(Some(absolute_root))
+    }
+
+    fn implementation_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for source in &self.implementation {
+            let file = source.canonicalize().map_err(|source_err| Error::Input {
+                path: source.clone(),
+                source: source_err,
+            })?;
+            utils::push_unique(&mut files, file);
+        }
+        Ok(files)
+    }
+
+    // inputs with folders replaced by their top-level headers
+    fn input_files(&self) -> Result<Vec<PathBuf>> {
+        if let [file] = self.inputs.as_slice() {
+            if !file.is_dir() && self.implementation.is_empty() {
+                // kept as given, the preprocessor reports it relative to the current directory
+                fs::metadata(file).map_err(|source| Error::Input {
+                    path: file.clone(),
+                    source,
+                })?;
+                return Ok(vec![file.clone()]);
+            }
+        }
+
+        let mut files = Vec::new();
+        for input in &self.inputs {
+            let headers = if input.is_dir() {
+                amalgamate::top_level_headers(input)
+            } else {
+                input.canonicalize().map(|file| vec![file])
+            }
+            .map_err(|source| Error::Input {
+                path: input.clone(),
+                source,
+            })?;
+            headers
+                .into_iter()
+                .for_each(|header| utils::push_unique(&mut files, header));
+        }
+
+        if files.is_empty() {
+            return Err(Error::NoHeaders);
+        }
+        Ok(files)
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// a GNU executable only preprocesses for the target its name is prefixed with
+fn check_target(target: Option<&str>, program: &Path, dialect: Dialect) -> Result<()> {
+    let Some(target) = target else {
+        return Ok(());
+    };
+    let name = program.file_name().unwrap_or_default().to_string_lossy();
+    if dialect == Dialect::Gnu && !name.starts_with(&format!("{target}-")) {
+        return Err(Error::UnsupportedTarget {
+            target: String::from(target),
+            name: name.into_owned(),
+        });
+    }
+    Ok(())
+}
+
+// `-D`, `-U` and `-include` options, along with their separate value if any
+fn macro_opts(opts: &[String]) -> Vec<&str> {
+    let mut macro_opts = Vec::new();
+    let mut opts = opts.iter();
+    while let Some(opt) = opts.next() {
+        if ["-D", "-U", "-include"].contains(&opt.as_str()) {
+            macro_opts.push(opt.as_str());
+            macro_opts.extend(opts.next().map(String::as_str));
+        } else if opt.starts_with("-D") || opt.starts_with("-U") || opt.starts_with("-include") {
+            macro_opts.push(opt.as_str());
+        }
+    }
+    macro_opts
+}
+
+fn base_args(required: Vec<&'static str>, lang: &Lang, directives_only: bool) -> Vec<&'static str> {
+    let mut base_args = vec!["-x", lang.as_str()];
+    if directives_only {
+        base_args.push("-fdirectives-only"); // prevent macro expansion
+    }
+
+    utils::merge(required, base_args)
+}
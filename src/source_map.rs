@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::error::{Error, Result};
+use crate::utils;
+
+const VERSION: u32 = 1;
+
+/// line number and file, relative to the line root, an output line comes from
+pub type Location = Option<(usize, Rc<str>)>;
+
+fn line_directive(linenum: usize, path: &str) -> String {
+    format!("#line {linenum} \"{}\"", utils::escape_string(path))
+}
+
+/// range of consecutive output lines coming from consecutive lines of a source file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub output_start: usize, // first output line, 1-based
+    pub output_end: usize,   // last output line, inclusive
+    pub source: String,      // relative to the line root
+    pub source_line: usize,  // line of `source` matching `output_start`
+}
+
+/// maps the lines of a generated single header back to the original files
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    pub version: u32,
+    pub file: String,
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    pub(crate) fn new() -> Self {
+        SourceMap {
+            version: VERSION,
+            ..Default::default()
+        }
+    }
+
+    /// path of the source map written next to `output`
+    pub fn sidecar_path(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".map");
+        PathBuf::from(path)
+    }
+
+    pub fn read(path: &Path) -> Result<SourceMap> {
+        let file = File::open(path).map_err(|source| Error::Input {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        serde_json::from_reader(file).map_err(|source| Error::InvalidSourceMap {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// original file and line of the 1-based `output_line`
+    pub fn locate(&self, output_line: usize) -> Option<(&str, usize)> {
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|m| (m.output_start..=m.output_end).contains(&output_line))?;
+        let line = mapping.source_line + (output_line - mapping.output_start);
+        Some((&mapping.source, line))
+    }
+
+    fn push(&mut self, output_line: usize, source: &str, source_line: usize) {
+        if let Some(last) = self.mappings.last_mut() {
+            let follows = last.output_end + 1 == output_line
+                && last.source == source
+                && last.source_line + (output_line - last.output_start) == source_line;
+            if follows {
+                last.output_end = output_line;
+                return;
+            }
+        }
+        self.mappings.push(Mapping {
+            output_start: output_line,
+            output_end: output_line,
+            source: String::from(source),
+            source_line,
+        });
+    }
+
+    /// write `lines` at the end of `out`, mapping them to their `locations`
+    ///
+    /// if `line_directives`, a `#line` directive precedes the lines that don't follow the previous one
+    pub(crate) fn write_lines(
+        &mut self,
+        out: &mut Vec<u8>,
+        lines: &[String],
+        locations: &[Location],
+        line_directives: bool,
+    ) -> io::Result<()> {
+        let mut output_line = out.iter().filter(|&&c| c == b'\n').count() + 1;
+        // location of the line following the previous one
+        let mut next: Option<(usize, &str)> = None;
+
+        for (index, line) in lines.iter().enumerate() {
+            if let Some(Some((source_line, source))) = locations.get(index) {
+                if line_directives && next != Some((*source_line, source)) {
+                    writeln!(out, "{}", line_directive(*source_line, source))?;
+                    output_line += 1;
+                }
+                self.push(output_line, source, *source_line);
+                next = Some((source_line + 1, source));
+            } else {
+                next = None;
+            }
+            writeln!(out, "{line}")?;
+            output_line += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `#ifndef X` then `lines`, located in `a.h` except for `gen` and the skipped line 5
+    fn write(line_directives: bool) -> (String, SourceMap) {
+        let lines = ["a", "b", "#line 10 \"gram.y\"", "c", "gen", "d"].map(String::from);
+        let file: Rc<str> = "a.h".into();
+        let locations: Vec<Location> = [Some(1), Some(2), Some(3), Some(4), None, Some(6)]
+            .iter()
+            .map(|linenum| linenum.map(|linenum| (linenum, file.clone())))
+            .collect();
+        let mut out = b"#ifndef X\n".to_vec();
+        let mut source_map = SourceMap::new();
+        source_map
+            .write_lines(&mut out, &lines, &locations, line_directives)
+            .unwrap();
+        (String::from_utf8(out).unwrap(), source_map)
+    }
+
+    #[test]
+    fn writes_lines_with_line_directives() {
+        let (output, source_map) = write(true);
+        let expected =
+            "#ifndef X\n#line 1 \"a.h\"\na\nb\n#line 10 \"gram.y\"\nc\ngen\n#line 6 \"a.h\"\nd\n";
+        assert_eq!(output, expected);
+        assert_eq!(source_map.locate(3), Some(("a.h", 1)));
+        assert_eq!(source_map.locate(6), Some(("a.h", 4)));
+        assert_eq!(source_map.locate(9), Some(("a.h", 6)));
+    }
+
+    #[test]
+    fn keeps_the_written_line_directives() {
+        let (output, source_map) = write(false);
+        let expected = "#ifndef X\na\nb\n#line 10 \"gram.y\"\nc\ngen\nd\n";
+        assert_eq!(output, expected);
+        assert_eq!(source_map.mappings.len(), 2);
+        assert_eq!(source_map.locate(2), Some(("a.h", 1)));
+        assert_eq!(source_map.locate(4), Some(("a.h", 3)));
+        assert_eq!(source_map.locate(5), Some(("a.h", 4)));
+        assert_eq!(source_map.locate(6), None); // generated
+        assert_eq!(source_map.locate(7), Some(("a.h", 6)));
+        assert_eq!(source_map.locate(1), None);
+        assert_eq!(source_map.locate(8), None);
+    }
+}
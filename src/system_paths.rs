@@ -1,22 +1,94 @@
-use crate::utils::stderr_command;
-use anyhow::{anyhow, Result};
-use radix_trie::{Trie, TrieCommon};
+use crate::error::{Error, Result};
+use crate::probe::{Program, SearchListFormat};
+use crate::utils::{self, stderr_command};
+use anyhow::anyhow;
 use std::{
+    fmt,
     path::{Path, PathBuf},
-    process::Command,
 };
 
+enum SearchList {
+    Quote, // `#include "..."` only
+    Angle, // `#include <...>` and `#include "..."`
+}
+
 pub struct SearchPaths {
-    search_paths: Trie<PathBuf, ()>,
+    // `#include <...>` search paths in the order the preprocessor looks through them
+    ordered_paths: Vec<PathBuf>,
+    // paths only searched by `#include "..."` (`-iquote`), in search order
+    quote_paths: Vec<PathBuf>,
+    // `-I` paths, searched but not system paths
+    local_paths: Vec<PathBuf>,
+    // `--sysroot` the system paths are in
+    sysroot: Option<PathBuf>,
+}
+
+/// `#include` directive name
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncludeName {
+    pub name: String,
+    pub quoted: bool,
+    // Objective-C `#import`, only included once
+    pub import: bool,
+}
+
+impl fmt::Display for IncludeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keyword = match self.import {
+            true => "import",
+            false => "include",
+        };
+        match self.quoted {
+            true => write!(f, "#{keyword} \"{}\"", self.name),
+            false => write!(f, "#{keyword} <{}>", self.name),
+        }
+    }
+}
+
+fn option_strings<'a>(opts: &'a [String], flag: &str) -> Vec<&'a str> {
+    let mut values = Vec::new();
+    let mut opts = opts.iter();
+    while let Some(opt) = opts.next() {
+        let value = match opt.strip_prefix(flag) {
+            Some("") => opts.next().map(String::as_str),
+            Some(value) => Some(value),
+            None => None,
+        };
+        values.extend(value);
+    }
+    values
+}
+
+// existing directories of `flag`, a leading `=` standing for the sysroot
+fn option_values(opts: &[String], flag: &str, sysroot: Option<&Path>) -> Vec<PathBuf> {
+    let in_sysroot = |value: &str| {
+        let path = value.strip_prefix('=')?.trim_start_matches('/');
+        Some(sysroot?.join(path))
+    };
+    option_strings(opts, flag)
+        .into_iter()
+        .filter_map(|value| {
+            let path = in_sysroot(value).unwrap_or_else(|| PathBuf::from(value));
+            path.canonicalize().ok()
+        })
+        .collect()
+}
+
+fn sysroot_option(opts: &[String]) -> Option<PathBuf> {
+    option_strings(opts, "--sysroot")
+        .last()
+        .map(|value| value.strip_prefix('=').unwrap_or(value))
+        .and_then(|value| Path::new(value).canonicalize().ok())
 }
 
 impl SearchPaths {
-    pub fn new<'a, 'b>(
-        program: &'a str,
-        base_args: &Vec<&'b str>,
+    pub fn new(
+        program: &Program,
+        format: SearchListFormat,
+        base_args: &Vec<&str>,
         extra_args: &Vec<String>,
     ) -> Result<SearchPaths> {
-        let mut command = Command::new(program);
+        let mut command = program.command();
 
         command
             .args(base_args)
@@ -25,45 +97,212 @@ impl SearchPaths {
             .args(extra_args);
 
         let stderr_lines = stderr_command("C preprocessor", command)?;
-        let mut parsing_search_list: bool = false;
+        let sysroot = sysroot_option(extra_args);
+        let mut parsing_search_list: Option<SearchList> = None;
 
-        let mut search_paths = Trie::new();
+        let mut ordered_paths = Vec::new();
+        let mut quote_paths = Vec::new();
 
         for line in stderr_lines {
+            if line.starts_with("#include \"...\" search starts here:") {
+                parsing_search_list = Some(SearchList::Quote);
+                continue;
+            }
             if line.starts_with("#include <...> search starts here:") {
-                parsing_search_list = true;
+                parsing_search_list = Some(SearchList::Angle);
                 continue;
             }
             if line.starts_with("End of search list.") {
                 break;
             }
 
-            if parsing_search_list {
+            if let Some(search_list) = &parsing_search_list {
                 let line = line.trim(); // remove indentation
+
+                // macOS frameworks and header maps can't be named by an `#include`
+                let special =
+                    line.ends_with("(framework directory)") || line.ends_with("(headermap)");
+                if format == SearchListFormat::Annotated && special {
+                    continue;
+                }
                 let path = Path::new(line)
                     .canonicalize() // convert to absolute path
-                    .expect("preprocessor search path doesn't exists");
-                search_paths.insert(path, ());
+                    .map_err(|source| Error::SearchPath {
+                        path: PathBuf::from(line),
+                        source,
+                    })?;
+                match search_list {
+                    SearchList::Quote => quote_paths.push(path),
+                    SearchList::Angle => ordered_paths.push(path),
+                }
             }
         }
 
-        Ok(SearchPaths { search_paths })
+        Ok(SearchPaths {
+            ordered_paths,
+            quote_paths,
+            local_paths: option_values(extra_args, "-I", sysroot.as_deref()),
+            sysroot,
+        })
+    }
+
+    /// search paths given by `-iquote`, `-I`, `-isystem` and `-idirafter` options alone,
+    /// without a preprocessor to ask for its own
+    pub fn from_options(opts: &[String]) -> SearchPaths {
+        let sysroot = sysroot_option(opts);
+        let values = |flag| option_values(opts, flag, sysroot.as_deref());
+        let local_paths = values("-I");
+
+        let mut ordered_paths = Vec::new();
+        for path in [
+            local_paths.clone(),
+            values("-isystem"),
+            values("-idirafter"),
+        ]
+        .concat()
+        {
+            utils::push_unique(&mut ordered_paths, path);
+        }
+        SearchPaths {
+            ordered_paths,
+            quote_paths: values("-iquote"),
+            local_paths,
+            sysroot,
+        }
+    }
+
+    /// `#include <...>` search list, in search order
+    pub fn ordered_paths(&self) -> &[PathBuf] {
+        &self.ordered_paths
+    }
+
+    /// `#include "..."` only search list, in search order
+    pub fn quote_paths(&self) -> &[PathBuf] {
+        &self.quote_paths
+    }
+
+    /// `-I` search paths
+    pub fn local_paths(&self) -> &[PathBuf] {
+        &self.local_paths
+    }
+
+    // search paths of the consumers of the single header, `-I` paths are not shipped with it
+    fn system_paths<'a>(&'a self, paths: &'a [PathBuf]) -> impl Iterator<Item = &'a PathBuf> {
+        paths.iter().filter(|path| !self.local_paths.contains(path))
+    }
+
+    /// `--sysroot` the preprocessor was run with
+    pub fn sysroot(&self) -> Option<&Path> {
+        self.sysroot.as_deref()
+    }
+
+    /// `absolute_path` is under a system search path
+    pub fn is_system_header(&self, absolute_path: &Path) -> bool {
+        self.system_paths(&self.ordered_paths)
+            .any(|dir| absolute_path.starts_with(dir))
+    }
+
+    /// file `include` resolves to through the system search paths
+    pub fn resolve(&self, include: &IncludeName) -> Option<PathBuf> {
+        let quote_paths = match include.quoted {
+            true => &self.quote_paths[..],
+            false => &[],
+        };
+        self.system_paths(quote_paths)
+            .chain(self.system_paths(&self.ordered_paths))
+            .find_map(|dir| dir.join(&include.name).canonicalize().ok())
+    }
+
+    /// every name `absolute_path` can be included with, in search order
+    pub fn include_names(&self, absolute_path: &Path) -> Vec<IncludeName> {
+        let quoted = self.system_paths(&self.quote_paths).map(|dir| (dir, true));
+        let angle = self
+            .system_paths(&self.ordered_paths)
+            .map(|dir| (dir, false));
+        quoted
+            .chain(angle)
+            .filter_map(|(dir, quoted)| {
+                let name = absolute_path.strip_prefix(dir).ok()?;
+                Some(IncludeName {
+                    name: name.display().to_string(),
+                    quoted,
+                    import: false,
+                })
+            })
+            .collect()
     }
 
-    pub fn cleanup_path(&self, absolute_path: &PathBuf) -> Result<String> {
-        let Some(search_path_trie) = self.search_paths.get_ancestor(absolute_path) else {
+    /// `#include` directive finding `absolute_path` through the deepest matching search path
+    ///
+    /// quoted if it is found through a quote only search path
+    pub fn include_directive(&self, absolute_path: &Path) -> anyhow::Result<IncludeName> {
+        let quote_path = deepest_path(&self.quote_paths, absolute_path);
+        let search_path = deepest_path(&self.ordered_paths, absolute_path);
+
+        match (quote_path, search_path) {
+            (Some(quote_path), search_path)
+                if search_path.is_none_or(|search_path| {
+                    quote_path.components().count() > search_path.components().count()
+                }) =>
+            {
+                let stripped_path = absolute_path.strip_prefix(quote_path)?;
+                Ok(IncludeName {
+                    name: stripped_path.display().to_string(),
+                    quoted: true,
+                    import: false,
+                })
+            }
+            _ => Ok(IncludeName {
+                name: self.cleanup_path(absolute_path)?,
+                quoted: false,
+                import: false,
+            }),
+        }
+    }
+
+    pub fn cleanup_path(&self, absolute_path: &Path) -> anyhow::Result<String> {
+        let Some(search_path) = deepest_path(&self.ordered_paths, absolute_path) else {
             return Err(anyhow!(
                 "Path {} is not in search paths",
                 absolute_path.display()
             ));
         };
-        let prefix = search_path_trie.key().unwrap(); // unwrap is safe because we know the path is in the trie
-        let stripped_path = absolute_path
-            .strip_prefix(prefix)
-            .expect("search path prefix was found but couldn't be stripped")
-            .display()
-            .to_string();
-
-        Ok(stripped_path)
+        let stripped_path = absolute_path.strip_prefix(search_path)?;
+
+        Ok(stripped_path.display().to_string())
+    }
+}
+
+// the deepest of `paths` holding `absolute_path`, compared by components: `/a/b` doesn't hold `/a/b-c`
+fn deepest_path<'a>(paths: &'a [PathBuf], absolute_path: &Path) -> Option<&'a PathBuf> {
+    paths
+        .iter()
+        .filter(|path| absolute_path.starts_with(path))
+        .max_by_key(|path| path.components().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deepest_search_path_by_components() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        for folder in ["a/b", "a/b-c"] {
+            std::fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        let opts = [
+            format!("-isystem{}", dir.join("a").display()),
+            format!("-isystem{}", dir.join("a/b").display()),
+        ];
+        let search_paths = SearchPaths::from_options(&opts);
+
+        let header = dir.join("a/b-c/d.h");
+        assert_eq!(search_paths.cleanup_path(&header).unwrap(), "b-c/d.h");
+        let include = search_paths.include_directive(&header).unwrap();
+        assert_eq!(include.to_string(), "#include <b-c/d.h>");
+        let header = dir.join("a/b/d.h");
+        assert_eq!(search_paths.cleanup_path(&header).unwrap(), "d.h");
     }
 }
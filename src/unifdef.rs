@@ -0,0 +1,336 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::directive::CONDITIONAL_REGEX;
+use crate::expand::strip_comments;
+use crate::expr::{self, Expr, KnownMacros};
+use crate::source_map::Location;
+
+// unifdef-style partial evaluation of conditionals
+// only conditionals depending on known macros are evaluated or simplified,
+// any other conditional is kept as written
+
+lazy_static! {
+    static ref IDENT_REGEX: Regex = Regex::new(r"^\s*(\w+)").unwrap();
+}
+
+enum Condition {
+    True,
+    False,
+    Written,            // doesn't depend on known macros, keep it as is
+    Simplified(String), // simplified expression
+}
+
+fn evaluate(kind: &str, expression: &str, known: &KnownMacros) -> Condition {
+    let expr = match kind {
+        "ifdef" | "elifdef" | "ifndef" | "elifndef" => {
+            let Some(name) = IDENT_REGEX.captures(expression) else {
+                return Condition::Written;
+            };
+            let defined = Expr::Defined(name[1].to_string());
+            match kind {
+                "ifdef" | "elifdef" => defined,
+                _ => Expr::Unary("!", Box::new(defined)),
+            }
+        }
+        _ => match expr::parse(expression) {
+            Some(expr) => expr,
+            None => return Condition::Written,
+        },
+    };
+    if !expr.mentions(known) {
+        return Condition::Written;
+    }
+    let simplified = expr.simplify(known, true);
+    match simplified.value() {
+        Some(0) => Condition::False,
+        Some(_) => Condition::True,
+        None => Condition::Simplified(simplified.to_string()),
+    }
+}
+
+struct Frame {
+    parent_visible: bool,
+    emitted: bool, // the chain's opening `#if` is kept in the output
+    taken: bool,   // one of the chain's branches is known to be true
+    visible: bool, // lines of the current branch are kept
+}
+
+struct Unifdef<'a> {
+    known: &'a KnownMacros,
+    stack: Vec<Frame>,
+    output: Vec<String>,
+}
+
+impl Unifdef<'_> {
+    fn visible(&self) -> bool {
+        self.stack.last().is_none_or(|frame| frame.visible)
+    }
+
+    fn emit(&mut self, lines: &[String]) {
+        self.output.extend(lines.iter().cloned());
+    }
+
+    fn open(&mut self, kind: &str, expression: &str, lines: &[String]) {
+        let parent_visible = self.visible();
+        let mut frame = Frame {
+            parent_visible,
+            emitted: false,
+            taken: false,
+            visible: false,
+        };
+        if parent_visible {
+            match evaluate(kind, expression, self.known) {
+                Condition::True => {
+                    frame.taken = true;
+                    frame.visible = true;
+                }
+                Condition::False => {}
+                Condition::Written => {
+                    self.emit(lines);
+                    frame.emitted = true;
+                    frame.visible = true;
+                }
+                Condition::Simplified(expression) => {
+                    self.output.push(format!("#if {expression}"));
+                    frame.emitted = true;
+                    frame.visible = true;
+                }
+            }
+        }
+        self.stack.push(frame);
+    }
+
+    fn elif(&mut self, kind: &str, expression: &str, lines: &[String]) {
+        let Some(frame) = self.stack.last() else {
+            return self.emit(lines); // unbalanced, leave it to the compiler
+        };
+        if !frame.parent_visible || frame.taken {
+            self.stack.last_mut().unwrap().visible = false;
+            return;
+        }
+        let emitted = frame.emitted;
+        // (visible, taken, emitted)
+        let state = match evaluate(kind, expression, self.known) {
+            Condition::True => {
+                if emitted {
+                    self.output.push(String::from("#else"));
+                }
+                (true, true, emitted)
+            }
+            Condition::False => (false, false, emitted),
+            Condition::Written if emitted => {
+                self.emit(lines);
+                (true, false, true)
+            }
+            Condition::Written => {
+                // previous branches were removed, this one opens the chain
+                let opening = match kind {
+                    "elifdef" => "ifdef",
+                    "elifndef" => "ifndef",
+                    _ => "if",
+                };
+                let (first, rest) = lines.split_first().unwrap();
+                let first = CONDITIONAL_REGEX
+                    .replace(first, |c: &regex::Captures| format!("#{opening}{}", &c[2]));
+                self.output.push(first.into_owned());
+                self.emit(rest);
+                (true, false, true)
+            }
+            Condition::Simplified(expression) => {
+                let directive = if emitted { "elif" } else { "if" };
+                self.output.push(format!("#{directive} {expression}"));
+                (true, false, true)
+            }
+        };
+        let frame = self.stack.last_mut().unwrap();
+        (frame.visible, frame.taken, frame.emitted) = state;
+    }
+
+    fn other_branch(&mut self, lines: &[String]) {
+        let Some(frame) = self.stack.last_mut() else {
+            return self.emit(lines);
+        };
+        frame.visible = frame.parent_visible && !frame.taken;
+        frame.taken = true;
+        if frame.visible && frame.emitted {
+            self.emit(lines);
+        }
+    }
+
+    fn close(&mut self, lines: &[String]) {
+        match self.stack.pop() {
+            Some(frame) if !frame.emitted => {}
+            _ => self.emit(lines),
+        }
+    }
+
+    fn feed(&mut self, lines: &[String], code: &str) {
+        let Some(conditional) = CONDITIONAL_REGEX.captures(code) else {
+            if self.visible() {
+                self.emit(lines);
+            }
+            return;
+        };
+        let (kind, expression) = (&conditional[1], &conditional[2]);
+        match kind {
+            "if" | "ifdef" | "ifndef" => self.open(kind, expression, lines),
+            "elif" | "elifdef" | "elifndef" => self.elif(kind, expression, lines),
+            "else" => self.other_branch(lines),
+            _ => self.close(lines),
+        }
+    }
+}
+
+/// evaluate or simplify the conditionals of `lines` depending on the `known` macros
+///
+/// returns the remaining lines and their `locations`
+pub fn unifdef(
+    lines: Vec<String>,
+    locations: Vec<Location>,
+    known: &KnownMacros,
+) -> (Vec<String>, Vec<Location>) {
+    if known.is_empty() {
+        return (lines, locations);
+    }
+
+    let mut unifdef = Unifdef {
+        known,
+        stack: Vec::new(),
+        output: Vec::new(),
+    };
+    let mut output_locations = Vec::new();
+    let mut in_comment = false;
+    let mut lines = lines.into_iter().zip(locations);
+
+    while let Some((line, location)) = lines.next() {
+        let was_in_comment = in_comment;
+        let mut code = strip_comments(&line, &mut in_comment);
+        let mut group = vec![line];
+        let mut group_locations = vec![location];
+
+        if !was_in_comment && code.trim_start().starts_with('#') {
+            // join continuation lines of the directive
+            while code.ends_with('\\') {
+                let Some((next, location)) = lines.next() else {
+                    break;
+                };
+                code.pop();
+                code.push_str(&strip_comments(&next, &mut in_comment));
+                group.push(next);
+                group_locations.push(location);
+            }
+        } else {
+            code.clear();
+        }
+
+        let start = unifdef.output.len();
+        unifdef.feed(&group, &code);
+        // a directive rewritten on fewer lines comes from its first line
+        for index in 0..unifdef.output.len() - start {
+            let location = group_locations.get(index).unwrap_or(&group_locations[0]);
+            output_locations.push(location.clone());
+        }
+    }
+    (unifdef.output, output_locations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(input: &str, macros: &[(&str, Option<&str>)]) -> Vec<String> {
+        let known = macros
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.map(String::from)))
+            .collect();
+        let lines: Vec<String> = input.lines().map(String::from).collect();
+        let locations = vec![None; lines.len()];
+        unifdef(lines, locations, &known).0
+    }
+
+    #[test]
+    fn partially_evaluated_condition() {
+        let input = "#if defined(X) && Y\na\n#endif";
+        assert_eq!(run(input, &[("X", Some("1"))]), ["#if Y", "a", "#endif"]);
+        assert!(run(input, &[("X", None)]).is_empty());
+        assert_eq!(
+            run(input, &[("Y", Some("1"))]),
+            ["#if defined(X)", "a", "#endif"]
+        );
+        assert_eq!(
+            run(input, &[("Z", Some("1"))]),
+            input.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ifdef_and_else() {
+        let input = "#ifdef X\na\n#else\nb\n#endif\nc";
+        assert_eq!(run(input, &[("X", Some("1"))]), ["a", "c"]);
+        assert_eq!(run(input, &[("X", None)]), ["b", "c"]);
+        assert!(run("#ifndef X\na\n#endif", &[("X", Some("1"))]).is_empty());
+    }
+
+    #[test]
+    fn elif_chains() {
+        let input = "#if A\na\n#elif X\nx\n#elif B\nb\n#else\nc\n#endif";
+        assert_eq!(
+            run(input, &[("X", Some("1"))]),
+            ["#if A", "a", "#else", "x", "#endif"]
+        );
+        assert_eq!(
+            run(input, &[("X", None)]),
+            ["#if A", "a", "#elif B", "b", "#else", "c", "#endif"]
+        );
+        // removed first branches, the next one opens the chain
+        let input = "#if X\nx\n#elifdef B\nb\n#elif Y && C\nc\n#endif";
+        assert_eq!(
+            run(input, &[("X", None), ("Y", Some("1"))]),
+            ["#ifdef B", "b", "#elif C", "c", "#endif"]
+        );
+        assert_eq!(run(input, &[("X", Some("2"))]), ["x"]);
+    }
+
+    #[test]
+    fn nested_frames() {
+        let input = "#ifdef X\n#if A\na\n#elif B\nb\n#endif\n#else\n#if C\nc\n#endif\n#endif";
+        assert_eq!(
+            run(input, &[("X", Some("1"))]),
+            ["#if A", "a", "#elif B", "b", "#endif"]
+        );
+        assert_eq!(run(input, &[("X", None)]), ["#if C", "c", "#endif"]);
+
+        // known conditionals inside a kept one
+        let input = "#if A\n#ifdef X\na\n#endif\n#else\n#ifndef X\nb\n#endif\n#endif";
+        assert_eq!(
+            run(input, &[("X", Some("1"))]),
+            ["#if A", "a", "#else", "#endif"]
+        );
+    }
+
+    #[test]
+    fn continuation_lines() {
+        let input = "#if defined(X) && \\\n  Y\na\n#endif";
+        assert_eq!(run(input, &[("X", Some("1"))]), ["#if Y", "a", "#endif"]);
+    }
+
+    #[test]
+    fn keeps_the_locations_of_the_lines() {
+        let input = "#ifdef X\nx\n#endif\n#if defined(X) || \\\n  Y\ny\n#endif";
+        let lines: Vec<String> = input.lines().map(String::from).collect();
+        let file: std::rc::Rc<str> = "a.h".into();
+        let locations = (1..=lines.len())
+            .map(|linenum| Some((linenum, file.clone())))
+            .collect();
+        let known = [(String::from("X"), None)].into_iter().collect();
+        let (lines, locations) = unifdef(lines, locations, &known);
+        assert_eq!(lines, ["#if Y", "y", "#endif"]);
+        let linenums: Vec<_> = locations
+            .iter()
+            .flatten()
+            .map(|(linenum, _)| *linenum)
+            .collect();
+        assert_eq!(linenums, [4, 6, 7]);
+    }
+}
@@ -1,81 +1,181 @@
-use anyhow::{Context, Result};
 use std::{
-    io::BufRead,
-    process::{Command, Output},
+    fs,
+    io::{self, BufRead, Write},
+    path::{Component, Path, PathBuf},
+    process::{Command, Output, Stdio},
 };
 
-// Anyhow tools
-macro_rules! lazy_context {
-    ($expr:expr, $format:tt, $($arg:tt)*) => { Context::with_context($expr, || format!($format, $($arg)*)) }
-}
-
-// convert IntoIter of std:::result::Result into anyhow::Result<Vec>
-pub trait TryResFold {
-    type Item;
-    fn try_res_fold(self) -> anyhow::Result<Vec<Self::Item>>;
-}
-
-impl<Item, Err, I> TryResFold for I
-where
-    I: IntoIterator<Item = std::result::Result<Item, Err>>,
-    Err: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
-{
-    type Item = Item;
-
-    fn try_res_fold(self) -> anyhow::Result<Vec<Self::Item>> {
-        let try_fold = |mut vec: Vec<Item>, line| -> anyhow::Result<Vec<Item>> {
-            vec.push(line?);
-            anyhow::Result::Ok(vec)
-        };
-        self.into_iter().try_fold(Vec::new(), try_fold)
-    }
-}
+use crate::error::{Error, Result};
 
 // Command tools
 fn run_command(description: &'static str, mut command: Command) -> Result<Output> {
-    let output = lazy_context!(command.output(), "Failed to run {}", description)?;
+    let output = command.output().map_err(|source| Error::Command {
+        description,
+        source,
+    })?;
+    check_status(description, output)
+}
 
+fn check_status(description: &'static str, output: Output) -> Result<Output> {
     if !output.status.success() {
-        panic!(
-            "C preprocessor exited with non-zero status code:\n{}",
-            String::from_utf8(output.stderr)
-                .expect("C preprocessor exited with non-zero and stderr isn't utf-8"),
-        );
+        return Err(Error::CommandFailed {
+            description,
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
     }
     Ok(output)
 }
 
+fn output_lines(description: &'static str, output: &[u8]) -> Result<Vec<String>> {
+    output
+        .lines()
+        .collect::<io::Result<_>>()
+        .map_err(|source| Error::Command {
+            description,
+            source,
+        })
+}
+
 pub fn stdout_command(description: &'static str, command: Command) -> Result<Vec<String>> {
     let output = run_command(description, command)?;
-    output.stdout.lines().try_res_fold()
+    output_lines(description, &output.stdout)
+}
+
+// same as `stdout_command` but feeds `input` to the command's stdin
+pub fn stdout_command_with_input(
+    description: &'static str,
+    command: Command,
+    input: String,
+) -> Result<Vec<String>> {
+    let (stdout, _) = output_command_with_input(description, command, input)?;
+    Ok(stdout)
+}
+
+// stdout and stderr lines of `command` fed with `input`
+pub fn output_command_with_input(
+    description: &'static str,
+    mut command: Command,
+    input: String,
+) -> Result<(Vec<String>, Vec<String>)> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let command_error = |source| Error::Command {
+        description,
+        source,
+    };
+    let mut child = command.spawn().map_err(command_error)?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // write from another thread so a full stdout pipe can't deadlock us
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = child.wait_with_output().map_err(command_error)?;
+    let written = writer.join().expect("stdin writer thread panicked");
+    written.map_err(command_error)?;
+
+    let output = check_status(description, output)?;
+    Ok((
+        output_lines(description, &output.stdout)?,
+        output_lines(description, &output.stderr)?,
+    ))
 }
 
 pub fn stderr_command(description: &'static str, command: Command) -> Result<Vec<String>> {
     let output = run_command(description, command)?;
-    output.stderr.lines().try_res_fold()
+    output_lines(description, &output.stderr)
 }
 
-pub trait LazyForEach {
-    type Item;
-    fn lazy_for_each<Action>(self, action: Action) -> impl FnOnce()
-    where
-        Action: FnMut(Self::Item);
+pub fn push_unique<T: PartialEq>(vec: &mut Vec<T>, value: T) {
+    if !vec.contains(&value) {
+        vec.push(value);
+    }
+}
+
+pub fn merge<T>(left: Vec<T>, right: Vec<T>) -> Vec<T> {
+    left.into_iter().chain(right).collect()
+}
+
+// escape `\` and `"` to write `text` in a C string literal
+pub fn escape_string(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// File tools
+
+// `path` relative to `base`, both absolute
+pub fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let parents = base.components().skip(common).map(|_| Component::ParentDir);
+    parents
+        .chain(path.components().skip(common))
+        .map(|component| component.as_os_str())
+        .collect()
 }
 
-impl<Item, I> LazyForEach for I
-where
-    I: IntoIterator<Item = Item>,
-{
-    type Item = Item;
+// atomically replace `path` with `content` through a temporary file in the same folder
+// nothing is written if `path` already holds `content`, preserving its mtime
+// the permissions of `path` are kept, a new file gets the ones `fs::write` would give it
+pub fn replace_file_if_changed(path: &Path, content: &[u8]) -> io::Result<()> {
+    let permissions = match fs::read(path) {
+        Ok(current) if current == content => return Ok(()),
+        Ok(_) => Some(fs::metadata(path)?.permissions()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
 
-    fn lazy_for_each<Action>(self, action: Action) -> impl FnOnce()
-    where
-        Action: FnMut(Self::Item),
+    let folder = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut builder = tempfile::Builder::new();
+    // the temporary file is only readable by its owner by default
+    #[cfg(unix)]
     {
-        move || self.into_iter().for_each(action)
+        use std::os::unix::fs::PermissionsExt;
+        // restricted by the umask
+        builder.permissions(fs::Permissions::from_mode(0o666));
     }
+    let mut tmp = builder.tempfile_in(folder)?;
+    if let Some(permissions) = permissions {
+        fs::set_permissions(tmp.path(), permissions)?;
+    }
+    tmp.write_all(content)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|err| err.error)?;
+    Ok(())
 }
 
-pub fn merge<T>(left: Vec<T>, right: Vec<T>) -> Vec<T> {
-    left.into_iter().chain(right.into_iter()).collect()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_file_keeps_its_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.h");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        replace_file_if_changed(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(mode(&path), 0o640);
+
+        // same as any new file
+        let reference = dir.path().join("reference.h");
+        fs::write(&reference, "").unwrap();
+        let path = dir.path().join("new.h");
+        replace_file_if_changed(&path, b"new").unwrap();
+        assert_eq!(mode(&path), mode(&reference));
+    }
 }